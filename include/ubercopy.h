@@ -0,0 +1,131 @@
+/*
+ * C interface to the ubercopy library. See src/capi.rs for the details of
+ * each function.
+ *
+ * Every object returned by this library is opaque and must be freed with the
+ * corresponding `_free` function. Strings inside a report are owned by the
+ * report and remain valid until it is freed.
+ */
+#ifndef UBERCOPY_H
+#define UBERCOPY_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Flags for ubercopy_manifest_parse and ubercopy_state_parse. The sandbox
+ * flags keep paths from escaping src_dir and dest_dir. */
+#define UBERCOPY_SANDBOX_SRC 1
+#define UBERCOPY_SANDBOX_DEST 2
+#define UBERCOPY_FORMAT_TSV 4
+#define UBERCOPY_FORMAT_JSON_LINES 8
+#define UBERCOPY_FORMAT_NULL 16
+#define UBERCOPY_ALLOW_EMPTY_GLOBS 32
+
+/* Error codes returned by ubercopy_report_error. */
+#define UBERCOPY_OK 0
+#define UBERCOPY_ERROR_OVERLAP 1
+#define UBERCOPY_ERROR_DUPLICATES 2
+#define UBERCOPY_ERROR_MISSING_SRCS 3
+#define UBERCOPY_ERROR_CREATE_DIRS 4
+#define UBERCOPY_ERROR_DELETE 5
+#define UBERCOPY_ERROR_DELETE_DIRS 6
+#define UBERCOPY_ERROR_COPY 7
+#define UBERCOPY_ERROR_VERIFY_INCOMPLETE 8
+#define UBERCOPY_ERROR_VERIFY_ERRORS 9
+#define UBERCOPY_ERROR_CANCELLED 10
+#define UBERCOPY_ERROR_MULTIPLE 11
+#define UBERCOPY_ERROR_PANIC 12
+
+typedef enum ubercopy_event {
+    UBERCOPY_DELETED = 1,
+    UBERCOPY_DIR_DELETED = 2,
+    UBERCOPY_DIR_CREATED = 3,
+    UBERCOPY_COPIED = 4,
+    UBERCOPY_FAILED = 5,
+} ubercopy_event;
+
+typedef enum ubercopy_phase {
+    UBERCOPY_PHASE_CHECK_RACES = 1,
+    UBERCOPY_PHASE_DELETE = 2,
+    UBERCOPY_PHASE_DELETE_DIRS = 3,
+    UBERCOPY_PHASE_OUTDATED = 4,
+    UBERCOPY_PHASE_CREATE_DIRS = 5,
+    UBERCOPY_PHASE_COPY = 6,
+    UBERCOPY_PHASE_VERIFY = 7,
+} ubercopy_phase;
+
+/* A single per-file outcome. Strings are null if they don't apply. */
+typedef struct ubercopy_outcome {
+    ubercopy_event event;
+    ubercopy_phase phase;
+    const char *src;
+    const char *dest;
+    const char *error;
+} ubercopy_outcome;
+
+typedef struct ubercopy_manifest ubercopy_manifest;
+typedef struct ubercopy_state ubercopy_state;
+typedef struct ubercopy_options ubercopy_options;
+typedef struct ubercopy_report ubercopy_report;
+
+/* Manifests */
+ubercopy_manifest *ubercopy_manifest_new(void);
+ubercopy_manifest *ubercopy_manifest_parse(const uint8_t *buf, size_t len,
+                                           const char *src_dir,
+                                           const char *dest_dir, int flags,
+                                           char **error);
+size_t ubercopy_manifest_len(const ubercopy_manifest *manifest);
+void ubercopy_manifest_free(ubercopy_manifest *manifest);
+bool ubercopy_manifest_save_state(const ubercopy_manifest *manifest,
+                                  const char *path, char **error);
+
+/* States saved by earlier syncs */
+ubercopy_state *ubercopy_state_parse(const uint8_t *buf, size_t len,
+                                     const char *src_dir,
+                                     const char *dest_dir, int flags,
+                                     char **error);
+size_t ubercopy_state_len(const ubercopy_state *state);
+void ubercopy_state_free(ubercopy_state *state);
+
+/* Sync options */
+ubercopy_options *ubercopy_options_new(void);
+void ubercopy_options_free(ubercopy_options *options);
+void ubercopy_options_set_dryrun(ubercopy_options *options, bool value);
+void ubercopy_options_set_force(ubercopy_options *options, bool value);
+void ubercopy_options_set_verify_copy(ubercopy_options *options, bool value);
+void ubercopy_options_set_keep_going(ubercopy_options *options, bool value);
+void ubercopy_options_set_threads(ubercopy_options *options, size_t value);
+void ubercopy_options_set_retries(ubercopy_options *options, size_t value);
+void ubercopy_options_set_retry_delay_ms(ubercopy_options *options,
+                                         uint64_t value);
+
+/* Syncing */
+ubercopy_report *ubercopy_sync(const ubercopy_manifest *prev,
+                               const ubercopy_manifest *next,
+                               const ubercopy_options *options);
+ubercopy_report *ubercopy_sync_from_state(const ubercopy_state *prev,
+                                          const ubercopy_manifest *next,
+                                          const ubercopy_options *options,
+                                          ubercopy_manifest **state);
+
+/* Reports */
+int ubercopy_report_error(const ubercopy_report *report);
+const char *ubercopy_report_message(const ubercopy_report *report);
+size_t ubercopy_report_copied(const ubercopy_report *report);
+size_t ubercopy_report_len(const ubercopy_report *report);
+bool ubercopy_report_get(const ubercopy_report *report, size_t index,
+                         ubercopy_outcome *outcome);
+void ubercopy_report_free(ubercopy_report *report);
+
+void ubercopy_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* UBERCOPY_H */
@@ -18,29 +18,52 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+use std::ffi::OsString;
 use std::path::PathBuf;
 
 use clap::{App, AppSettings, Arg, ArgMatches};
 
+use ubercopy::{Format, ParseOptions, SyncOptions};
+
+/// Where the next manifest comes from.
+#[derive(Debug)]
+pub enum Source {
+    /// The output of a generator program and its arguments.
+    Generator(OsString, Vec<OsString>),
+
+    /// An existing manifest file.
+    File(PathBuf),
+
+    /// Standard input.
+    Stdin,
+}
+
 #[derive(Debug)]
 pub struct Args {
     pub dryrun: bool,
     pub force: bool,
     pub verify_copy: bool,
+    pub keep_going: bool,
     pub sandbox_src: bool,
     pub sandbox_dest: bool,
+    pub format: Format,
+    pub allow_empty_globs: bool,
     pub threads: usize,
     pub retries: usize,
+    pub src: PathBuf,
     pub dest: PathBuf,
     pub manifest: PathBuf,
-    pub program: String,
-    pub args: Vec<String>,
+    pub source: Source,
 }
 
 impl Args {
-    #[rustfmt::skip]
     pub fn parse() -> Self {
-        let matches = App::new("ubercopy")
+        Args::parse_matches(&Args::app().get_matches())
+    }
+
+    #[rustfmt::skip]
+    fn app() -> App<'static, 'static> {
+        App::new("ubercopy")
             .version(clap::crate_version!())
             .author(clap::crate_authors!())
             .about(clap::crate_description!())
@@ -63,8 +86,14 @@ impl Args {
                     .help("After copying, verify that all files match.")
                     .long("verify-copy"),
 
+                Arg::with_name("keep-going")
+                    .help("Keep going after a failure and report all failures \
+                          at the end.")
+                    .long("keep-going")
+                    .short("k"),
+
                 Arg::with_name("sandbox-src")
-                    .help("Don't allow source paths to escape the current \
+                    .help("Don't allow source paths to escape the source \
                           directory.")
                     .long("sandbox-src"),
 
@@ -77,6 +106,27 @@ impl Args {
                     .help("Implies both --sandbox-src and --sandbox-dest.")
                     .long("sandbox"),
 
+                Arg::with_name("format")
+                    .help("Format of the manifest. By default, it is detected \
+                          from the first entry.")
+                    .long("format")
+                    .takes_value(true)
+                    .possible_values(&["auto", "tsv", "jsonl"])
+                    .default_value("auto"),
+
+                Arg::with_name("null")
+                    .help("The manifest is made up of NUL-terminated source \
+                          and destination paths, like the output of \
+                          `find -print0`.")
+                    .long("null")
+                    .short("0")
+                    .conflicts_with("format"),
+
+                Arg::with_name("allow-empty-globs")
+                    .help("Only warn about glob sources that don't match any \
+                          files.")
+                    .long("allow-empty-globs"),
+
                 Arg::with_name("threads")
                     .help("Number of threads to use for copying.")
                     .takes_value(true)
@@ -92,28 +142,39 @@ impl Args {
                     .takes_value(true)
                     .default_value("5"),
 
+                Arg::with_name("src")
+                    .help("Makes all source paths relative to this path.")
+                    .long("src")
+                    .takes_value(true),
+
                 Arg::with_name("dest")
                     .help("Makes all destination paths relative to this path.")
                     .long("dest")
                     .takes_value(true),
 
+                Arg::with_name("from-file")
+                    .help("Read the manifest from this file instead of \
+                          running a generator. Use `-` to read it from \
+                          stdin.")
+                    .long("from-file")
+                    .takes_value(true)
+                    .conflicts_with("program"),
+
                 Arg::with_name("manifest")
                     .help("Path to the manifest to generate.")
                     .index(1)
                     .required(true),
 
                 Arg::with_name("program")
-                    .help("Generator program name.")
+                    .help("Generator program name. If this is `-`, the \
+                          manifest is read from stdin instead.")
                     .index(2)
-                    .required(true),
+                    .required_unless("from-file"),
 
                 Arg::with_name("args")
                     .help("Generator program arguments.")
                     .min_values(1),
             ])
-            .get_matches();
-
-        Args::parse_matches(&matches)
     }
 
     fn parse_matches<'a>(matches: &ArgMatches<'a>) -> Self {
@@ -121,23 +182,131 @@ impl Args {
             dryrun: matches.is_present("dryrun"),
             force: matches.is_present("force"),
             verify_copy: matches.is_present("verify-copy"),
+            keep_going: matches.is_present("keep-going"),
             sandbox_src: matches.is_present("sandbox")
                 || matches.is_present("sandbox-src"),
             sandbox_dest: matches.is_present("sandbox")
                 || matches.is_present("sandbox-dest"),
+            format: match matches.value_of("format") {
+                _ if matches.is_present("null") => Format::Null,
+                Some("tsv") => Format::Tsv,
+                Some("jsonl") => Format::JsonLines,
+                _ => Format::Auto,
+            },
+            allow_empty_globs: matches.is_present("allow-empty-globs"),
             threads: clap::value_t!(matches, "threads", usize)
                 .unwrap_or_else(|e| e.exit()),
             retries: clap::value_t!(matches, "retries", usize)
                 .unwrap_or_else(|e| e.exit()),
+            src: matches
+                .value_of_os("src")
+                .map_or(PathBuf::from(""), PathBuf::from),
             dest: matches
-                .value_of("dest")
+                .value_of_os("dest")
                 .map_or(PathBuf::from(""), PathBuf::from),
-            manifest: PathBuf::from(matches.value_of("manifest").unwrap()),
-            program: matches.value_of("program").unwrap().to_string(),
-            args: match matches.values_of("args") {
-                None => vec![],
-                Some(vals) => vals.map(String::from).collect(),
-            },
+            manifest: PathBuf::from(matches.value_of_os("manifest").unwrap()),
+            source: Args::source(matches).unwrap_or_else(|e| e.exit()),
         }
     }
+
+    fn source<'a>(matches: &ArgMatches<'a>) -> Result<Source, clap::Error> {
+        let program = matches.value_of_os("program");
+
+        Ok(match matches.value_of_os("from-file").or(program) {
+            Some(path) if path == "-" => {
+                if matches.is_present("args") {
+                    return Err(clap::Error::with_description(
+                        "Arguments can't be given when reading the manifest \
+                         from stdin",
+                        clap::ErrorKind::ArgumentConflict,
+                    ));
+                }
+
+                Source::Stdin
+            }
+            Some(path) if program.is_none() => Source::File(path.into()),
+            Some(program) => Source::Generator(
+                program.to_os_string(),
+                match matches.values_of_os("args") {
+                    None => vec![],
+                    Some(vals) => vals.map(OsString::from).collect(),
+                },
+            ),
+            None => unreachable!("clap requires a manifest source"),
+        })
+    }
+
+    /// Maps the command line arguments onto the options used for parsing
+    /// manifests.
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions::new()
+            .src_dir(&self.src)
+            .dest_dir(&self.dest)
+            .sandbox_src(self.sandbox_src)
+            .sandbox_dest(self.sandbox_dest)
+            .format(self.format)
+            .allow_empty_globs(self.allow_empty_globs)
+    }
+
+    /// Maps the command line arguments onto the options used for syncing.
+    pub fn sync_options(&self) -> SyncOptions {
+        SyncOptions::new()
+            .dryrun(self.dryrun)
+            .force(self.force)
+            .verify_copy(self.verify_copy)
+            .keep_going(self.keep_going)
+            .threads(self.threads)
+            .retries(self.retries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(args: &[&str]) -> Result<Source, clap::Error> {
+        let matches = Args::app().get_matches_from_safe(args)?;
+        Args::source(&matches)
+    }
+
+    #[test]
+    fn generator() {
+        match source(&["ubercopy", "m", "python", "gen.py"]).unwrap() {
+            Source::Generator(program, args) => {
+                assert_eq!(program, "python");
+                assert_eq!(args, ["gen.py"]);
+            }
+            source => panic!("unexpected source {:?}", source),
+        }
+    }
+
+    #[test]
+    fn stdin() {
+        assert!(matches!(
+            source(&["ubercopy", "m", "-"]).unwrap(),
+            Source::Stdin
+        ));
+        assert!(matches!(
+            source(&["ubercopy", "m", "--from-file", "-"]).unwrap(),
+            Source::Stdin
+        ));
+
+        let err = source(&["ubercopy", "m", "-", "x"]).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn from_file() {
+        match source(&["ubercopy", "m", "--from-file", "a/m.txt"]).unwrap() {
+            Source::File(path) => assert_eq!(path, PathBuf::from("a/m.txt")),
+            source => panic!("unexpected source {:?}", source),
+        }
+
+        let err = source(&["ubercopy", "m", "--from-file", "a", "python"])
+            .unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::ArgumentConflict);
+
+        let err = source(&["ubercopy", "m"]).unwrap_err();
+        assert_eq!(err.kind, clap::ErrorKind::MissingRequiredArgument);
+    }
 }
@@ -0,0 +1,208 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! An asynchronous version of [`sync`](crate::sync::sync) for use with
+//! [tokio](https://tokio.rs). This is only available with the `tokio` feature.
+//!
+//! The file system work is still done by a pool of threads, which runs on
+//! tokio's blocking thread pool. Dropping the future cancels the sync: any
+//! operations that are already in flight finish, but nothing new is started.
+
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::manifest::Manifest;
+use crate::sync::SyncOptions;
+
+/// Cancels the sync when dropped. If the future returned by `sync` is dropped
+/// before it completes, this tells the worker threads to stop.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Synchronizes the file system with the `next` manifest without blocking the
+/// async runtime. See [`sync`](crate::sync::sync) for the details of how this
+/// is done.
+///
+/// This must be called from within a tokio runtime.
+pub async fn sync(
+    prev: Arc<Manifest>,
+    next: Arc<Manifest>,
+    options: SyncOptions,
+) -> Result<usize, Error> {
+    let cancelled = Arc::new(AtomicBool::new(false));
+
+    let mut options = options;
+    options.cancelled = Some(cancelled.clone());
+
+    let _guard = CancelOnDrop(cancelled);
+
+    let task = tokio::task::spawn_blocking(move || {
+        crate::sync::sync(&prev, &next, &options)
+    });
+
+    match task.await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+
+        // The task was cancelled, which happens when the runtime shuts down.
+        Err(_) => Err(Error::Cancelled),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::error::ErrorKind;
+    use crate::faultfs::{FaultFs, Operation};
+    use crate::memfs::MemoryFs;
+    use crate::parser::ParseOptions;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "ubercopy-{}-{}",
+            name,
+            process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn manifest(dir: &Path) -> Manifest {
+        fs::write(dir.join("a.txt"), "a").unwrap();
+
+        let text = format!(
+            "{}\t{}\n",
+            dir.join("a.txt").display(),
+            dir.join("out/a.txt").display()
+        );
+
+        Manifest::parse_reader(io::Cursor::new(text), &ParseOptions::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn copies_files() {
+        let dir = temp_dir("async-copy");
+        let next = Arc::new(manifest(&dir));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let copied = runtime
+            .block_on(sync(
+                Arc::new(Manifest::new()),
+                next,
+                SyncOptions::new().threads(2).verify_copy(true),
+            ))
+            .unwrap();
+
+        assert_eq!(copied, 1);
+        assert_eq!(fs::read_to_string(dir.join("out/a.txt")).unwrap(), "a");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cancelled() {
+        let dir = temp_dir("async-cancel");
+        let next = manifest(&dir);
+
+        let mut options = SyncOptions::new().threads(2);
+        options.cancelled = Some(Arc::new(AtomicBool::new(true)));
+
+        let err =
+            crate::sync::sync(&Manifest::new(), &next, &options).unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::Cancelled);
+        assert!(!dir.join("out/a.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropping_the_future_cancels() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        let mut text = String::new();
+
+        for i in 0..8 {
+            let src = format!("src/{}", i);
+            fs.inner().write(&src, "x");
+            fs.slow_copy(&src, Duration::from_millis(100));
+            text.push_str(&format!("{}\tdest/{}\n", src, i));
+        }
+
+        let next =
+            Manifest::parse_reader(text.as_bytes(), &ParseOptions::new())
+                .unwrap();
+        let options = SyncOptions::new().threads(1).filesystem(fs.clone());
+
+        let copies = || {
+            (0..8)
+                .map(|i| fs.calls(Operation::Copy, format!("src/{}", i)))
+                .sum::<usize>()
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let task = runtime.spawn(sync(
+            Arc::new(Manifest::new()),
+            Arc::new(next),
+            options,
+        ));
+
+        // Wait for the first copy to start.
+        runtime.block_on(async {
+            while copies() == 0 {
+                tokio::task::yield_now().await;
+                thread::sleep(Duration::from_millis(5));
+            }
+        });
+
+        task.abort();
+        assert!(runtime.block_on(task).unwrap_err().is_cancelled());
+
+        // The copy that was in flight finishes, but no more are started.
+        let started = copies();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(copies(), started);
+        assert!(started < 8);
+    }
+}
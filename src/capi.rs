@@ -0,0 +1,1128 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! A C ABI for driving manifest syncs from other languages. The matching
+//! header is `include/ubercopy.h`.
+//!
+//! All objects are opaque and must be freed with the corresponding `_free`
+//! function. Strings returned inside a report are owned by the report and
+//! remain valid until the report is freed.
+
+#![allow(non_camel_case_types)]
+
+use std::any::Any;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::copyop::CopyOp;
+use crate::error::{Error, ErrorKind};
+use crate::manifest::{Manifest, State};
+use crate::observer::{Phase, SyncObserver, Target};
+use crate::parser::{Format, ParseOptions};
+use crate::sync::{sync, sync_from_state, SyncOptions};
+use crate::util;
+
+/// Don't allow source paths to escape the source directory.
+pub const UBERCOPY_SANDBOX_SRC: c_int = 1;
+
+/// Don't allow destination paths to escape the destination directory.
+pub const UBERCOPY_SANDBOX_DEST: c_int = 2;
+
+/// Parse the manifest as tab-separated values.
+pub const UBERCOPY_FORMAT_TSV: c_int = 4;
+
+/// Parse the manifest as JSON Lines.
+pub const UBERCOPY_FORMAT_JSON_LINES: c_int = 8;
+
+/// Parse the manifest as NUL-terminated source and destination pairs.
+pub const UBERCOPY_FORMAT_NULL: c_int = 16;
+
+/// Only warn about glob sources that don't match any files.
+pub const UBERCOPY_ALLOW_EMPTY_GLOBS: c_int = 32;
+
+/// The kind of a per-file outcome in a report.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ubercopy_event {
+    UBERCOPY_DELETED = 1,
+    UBERCOPY_DIR_DELETED = 2,
+    UBERCOPY_DIR_CREATED = 3,
+    UBERCOPY_COPIED = 4,
+    UBERCOPY_FAILED = 5,
+}
+
+/// Mirrors `Phase`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ubercopy_phase {
+    UBERCOPY_PHASE_CHECK_RACES = 1,
+    UBERCOPY_PHASE_DELETE = 2,
+    UBERCOPY_PHASE_DELETE_DIRS = 3,
+    UBERCOPY_PHASE_OUTDATED = 4,
+    UBERCOPY_PHASE_CREATE_DIRS = 5,
+    UBERCOPY_PHASE_COPY = 6,
+    UBERCOPY_PHASE_VERIFY = 7,
+}
+
+impl From<Phase> for ubercopy_phase {
+    fn from(phase: Phase) -> Self {
+        match phase {
+            Phase::CheckRaces => ubercopy_phase::UBERCOPY_PHASE_CHECK_RACES,
+            Phase::Delete => ubercopy_phase::UBERCOPY_PHASE_DELETE,
+            Phase::DeleteDirs => ubercopy_phase::UBERCOPY_PHASE_DELETE_DIRS,
+            Phase::Outdated => ubercopy_phase::UBERCOPY_PHASE_OUTDATED,
+            Phase::CreateDirs => ubercopy_phase::UBERCOPY_PHASE_CREATE_DIRS,
+            Phase::Copy => ubercopy_phase::UBERCOPY_PHASE_COPY,
+            Phase::Verify => ubercopy_phase::UBERCOPY_PHASE_VERIFY,
+        }
+    }
+}
+
+/// The error code of a report if the sync panicked.
+pub const UBERCOPY_ERROR_PANIC: c_int = 12;
+
+/// Returns the error code for the given kind of error. Zero means success.
+fn error_code(kind: ErrorKind) -> c_int {
+    match kind {
+        ErrorKind::Overlap => 1,
+        ErrorKind::Duplicates => 2,
+        ErrorKind::MissingSrcs => 3,
+        ErrorKind::CreateDirs => 4,
+        ErrorKind::Delete => 5,
+        ErrorKind::DeleteDirs => 6,
+        ErrorKind::Copy => 7,
+        ErrorKind::VerifyIncomplete => 8,
+        ErrorKind::VerifyErrors => 9,
+        ErrorKind::Cancelled => 10,
+        ErrorKind::Multiple => 11,
+    }
+}
+
+/// A single per-file outcome. Any of the strings may be null if they don't
+/// apply to the event.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ubercopy_outcome {
+    pub event: ubercopy_event,
+    pub phase: ubercopy_phase,
+    pub src: *const c_char,
+    pub dest: *const c_char,
+    pub error: *const c_char,
+}
+
+/// An owned version of `ubercopy_outcome`.
+struct Outcome {
+    event: ubercopy_event,
+    phase: Phase,
+    src: Option<CString>,
+    dest: Option<CString>,
+    error: Option<CString>,
+}
+
+pub struct ubercopy_manifest(Manifest);
+
+pub struct ubercopy_state(State);
+
+pub struct ubercopy_options(SyncOptions);
+
+pub struct ubercopy_report {
+    code: c_int,
+    copied: usize,
+    message: Option<CString>,
+    outcomes: Vec<Outcome>,
+}
+
+/// Converts a path to a C string. On Windows, paths that aren't valid Unicode
+/// are converted lossily.
+fn path_to_cstring(path: &Path) -> CString {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().into_owned().into_bytes();
+
+    string_to_cstring(bytes)
+}
+
+/// Converts a string to a C string, dropping any interior NUL bytes.
+fn string_to_cstring<S: Into<Vec<u8>>>(s: S) -> CString {
+    let mut bytes = s.into();
+    bytes.retain(|&b| b != 0);
+    CString::new(bytes).unwrap()
+}
+
+/// Runs `f`, returning `default` instead if it panics. Unwinding out of an
+/// `extern "C"` function aborts the caller, so every exported function goes
+/// through this.
+fn catch<T, F>(default: T, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(default)
+}
+
+/// Gets the message of a caught panic.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown error"
+    };
+
+    format!("panicked: {}", message)
+}
+
+/// Records every per-file event so that it can be handed back to the caller.
+#[derive(Default)]
+struct Recorder {
+    outcomes: Mutex<Vec<Outcome>>,
+}
+
+impl Recorder {
+    fn push(
+        &self,
+        event: ubercopy_event,
+        phase: Phase,
+        src: Option<&Path>,
+        dest: Option<&Path>,
+        error: Option<&io::Error>,
+    ) {
+        self.outcomes.lock().unwrap().push(Outcome {
+            event,
+            phase,
+            src: src.map(path_to_cstring),
+            dest: dest.map(path_to_cstring),
+            error: error.map(|err| string_to_cstring(err.to_string())),
+        });
+    }
+}
+
+impl SyncObserver for Recorder {
+    fn deleted(&self, path: &Path) {
+        let event = ubercopy_event::UBERCOPY_DELETED;
+        self.push(event, Phase::Delete, None, Some(path), None);
+    }
+
+    fn dir_deleted(&self, path: &Path) {
+        let event = ubercopy_event::UBERCOPY_DIR_DELETED;
+        self.push(event, Phase::DeleteDirs, None, Some(path), None);
+    }
+
+    fn dir_created(&self, path: &Path) {
+        let event = ubercopy_event::UBERCOPY_DIR_CREATED;
+        self.push(event, Phase::CreateDirs, None, Some(path), None);
+    }
+
+    fn copy_finished(&self, op: &CopyOp) {
+        let event = ubercopy_event::UBERCOPY_COPIED;
+        self.push(event, Phase::Copy, Some(&op.src), Some(&op.dest), None);
+    }
+
+    fn wants_progress(&self) -> bool {
+        false
+    }
+
+    fn failed(&self, phase: Phase, target: Target<'_>, error: &io::Error) {
+        let event = ubercopy_event::UBERCOPY_FAILED;
+        match target {
+            Target::Path(path) => {
+                self.push(event, phase, None, Some(path), Some(error))
+            }
+            Target::Op(op) => self.push(
+                event,
+                phase,
+                Some(&op.src),
+                Some(&op.dest),
+                Some(error),
+            ),
+        }
+    }
+}
+
+/// Creates an empty manifest. This is useful as the previous manifest on the
+/// first sync.
+#[no_mangle]
+pub extern "C" fn ubercopy_manifest_new() -> *mut ubercopy_manifest {
+    catch(ptr::null_mut(), || {
+        Box::into_raw(Box::new(ubercopy_manifest(Manifest::new())))
+    })
+}
+
+/// Parses a manifest from a buffer. Source and destination paths are made
+/// relative to `src_dir` and `dest_dir`, either of which may be null. `flags`
+/// is a combination of `UBERCOPY_SANDBOX_SRC`, `UBERCOPY_SANDBOX_DEST`,
+/// `UBERCOPY_ALLOW_EMPTY_GLOBS` and one of the `UBERCOPY_FORMAT_*` flags. The
+/// format is detected if none of them is given.
+///
+/// Returns null on failure, including if parsing panicked. If `error` is not
+/// null, it is then set to a message that must be freed with
+/// `ubercopy_string_free`.
+///
+/// # Safety
+///
+/// `buf` must point to `len` readable bytes. `src_dir` and `dest_dir` must
+/// each be null or a valid NUL-terminated string. `error` must be null or
+/// valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_manifest_parse(
+    buf: *const u8,
+    len: usize,
+    src_dir: *const c_char,
+    dest_dir: *const c_char,
+    flags: c_int,
+    error: *mut *mut c_char,
+) -> *mut ubercopy_manifest {
+    parse_with(error, || {
+        let options = parse_options(src_dir, dest_dir, flags)?;
+        Manifest::parse_reader(buffer(buf, len), &options)
+            .map(ubercopy_manifest)
+            .map_err(|err| err.to_string())
+    })
+}
+
+/// Runs `f`, boxing up what it parsed. On failure, including if `f` panicked,
+/// returns null and sets `error` to the message if it isn't null.
+unsafe fn parse_with<T, F>(error: *mut *mut c_char, f: F) -> *mut T
+where
+    F: FnOnce() -> Result<T, String>,
+{
+    match catch_error(f) {
+        Ok(value) => Box::into_raw(Box::new(value)),
+        Err(message) => {
+            set_error(error, message);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Runs `f`, turning a panic into an error message.
+fn catch_error<T, F>(f: F) -> Result<T, String>
+where
+    F: FnOnce() -> Result<T, String>,
+{
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(panic_message(&*payload)))
+}
+
+unsafe fn buffer<'a>(buf: *const u8, len: usize) -> &'a [u8] {
+    if buf.is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(buf, len)
+    }
+}
+
+/// Converts a NUL-terminated string to a path. Paths don't have to be valid
+/// UTF-8 on Unix.
+unsafe fn path_from_ptr(path: *const c_char) -> Option<PathBuf> {
+    util::path_from_bytes(CStr::from_ptr(path).to_bytes().to_vec()).ok()
+}
+
+/// Converts a directory that may be null to a path. A null directory is
+/// empty.
+unsafe fn dir_from_ptr(
+    dir: *const c_char,
+    what: &str,
+) -> Result<PathBuf, String> {
+    if dir.is_null() {
+        Ok(PathBuf::new())
+    } else {
+        path_from_ptr(dir)
+            .ok_or_else(|| format!("{} directory is not valid Unicode", what))
+    }
+}
+
+unsafe fn parse_options(
+    src_dir: *const c_char,
+    dest_dir: *const c_char,
+    flags: c_int,
+) -> Result<ParseOptions, String> {
+    let src_dir = dir_from_ptr(src_dir, "source")?;
+    let dest_dir = dir_from_ptr(dest_dir, "destination")?;
+
+    let format = if flags & UBERCOPY_FORMAT_TSV != 0 {
+        Format::Tsv
+    } else if flags & UBERCOPY_FORMAT_JSON_LINES != 0 {
+        Format::JsonLines
+    } else if flags & UBERCOPY_FORMAT_NULL != 0 {
+        Format::Null
+    } else {
+        Format::Auto
+    };
+
+    Ok(ParseOptions::new()
+        .src_dir(src_dir)
+        .dest_dir(dest_dir)
+        .sandbox_src(flags & UBERCOPY_SANDBOX_SRC != 0)
+        .sandbox_dest(flags & UBERCOPY_SANDBOX_DEST != 0)
+        .format(format)
+        .allow_empty_globs(flags & UBERCOPY_ALLOW_EMPTY_GLOBS != 0))
+}
+
+unsafe fn set_error(error: *mut *mut c_char, message: String) {
+    if !error.is_null() {
+        *error = string_to_cstring(message).into_raw();
+    }
+}
+
+/// Returns the number of operations in the manifest.
+///
+/// # Safety
+///
+/// `manifest` must be a valid manifest.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_manifest_len(
+    manifest: *const ubercopy_manifest,
+) -> usize {
+    catch(0, || (*manifest).0.operations().len())
+}
+
+/// Frees a manifest. Does nothing if `manifest` is null.
+///
+/// # Safety
+///
+/// `manifest` must be null or a manifest that has not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_manifest_free(
+    manifest: *mut ubercopy_manifest,
+) {
+    catch((), || {
+        if !manifest.is_null() {
+            drop(Box::from_raw(manifest));
+        }
+    })
+}
+
+/// Saves a manifest as state that can be read back with `ubercopy_state_parse`.
+/// The file is replaced atomically. Returns false on failure. If `error` is not
+/// null, it is then set to a message that must be freed with
+/// `ubercopy_string_free`.
+///
+/// # Safety
+///
+/// `manifest` must be valid. `path` must be a valid NUL-terminated string.
+/// `error` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_manifest_save_state(
+    manifest: *const ubercopy_manifest,
+    path: *const c_char,
+    error: *mut *mut c_char,
+) -> bool {
+    let result = catch_error(|| {
+        let path = path_from_ptr(path)
+            .ok_or_else(|| "path is not valid Unicode".to_owned())?;
+        (*manifest)
+            .0
+            .save_state(path)
+            .map_err(|err| err.to_string())
+    });
+
+    match result {
+        Ok(()) => true,
+        Err(message) => {
+            set_error(error, message);
+            false
+        }
+    }
+}
+
+/// Parses the state saved by a previous sync from a buffer. A plain manifest
+/// is also accepted, in which case `src_dir`, `dest_dir` and `flags` are used
+/// like with `ubercopy_manifest_parse`.
+///
+/// Returns null on failure, including if parsing panicked. If `error` is not
+/// null, it is then set to a message that must be freed with
+/// `ubercopy_string_free`.
+///
+/// # Safety
+///
+/// `buf` must point to `len` readable bytes. `src_dir` and `dest_dir` must
+/// each be null or a valid NUL-terminated string. `error` must be null or
+/// valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_state_parse(
+    buf: *const u8,
+    len: usize,
+    src_dir: *const c_char,
+    dest_dir: *const c_char,
+    flags: c_int,
+    error: *mut *mut c_char,
+) -> *mut ubercopy_state {
+    parse_with(error, || {
+        let options = parse_options(src_dir, dest_dir, flags)?;
+        State::parse(buffer(buf, len), &options)
+            .map(ubercopy_state)
+            .map_err(|err| err.to_string())
+    })
+}
+
+/// Returns the number of operations in the state.
+///
+/// # Safety
+///
+/// `state` must be a valid state.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_state_len(
+    state: *const ubercopy_state,
+) -> usize {
+    catch(0, || (*state).0.len())
+}
+
+/// Frees a state. Does nothing if `state` is null.
+///
+/// # Safety
+///
+/// `state` must be null or a state that has not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_state_free(state: *mut ubercopy_state) {
+    catch((), || {
+        if !state.is_null() {
+            drop(Box::from_raw(state));
+        }
+    })
+}
+
+/// Creates sync options with the default settings.
+#[no_mangle]
+pub extern "C" fn ubercopy_options_new() -> *mut ubercopy_options {
+    catch(ptr::null_mut(), || {
+        Box::into_raw(Box::new(ubercopy_options(SyncOptions::new())))
+    })
+}
+
+/// Frees sync options. Does nothing if `options` is null.
+///
+/// # Safety
+///
+/// `options` must be null or options that have not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_options_free(options: *mut ubercopy_options) {
+    catch((), || {
+        if !options.is_null() {
+            drop(Box::from_raw(options));
+        }
+    })
+}
+
+/// Applies one of the `SyncOptions` builder methods to `options`.
+unsafe fn update_options<F>(options: *mut ubercopy_options, f: F)
+where
+    F: FnOnce(SyncOptions) -> SyncOptions,
+{
+    catch((), || {
+        let options = &mut (*options).0;
+        *options = f(options.clone());
+    })
+}
+
+/// See `SyncOptions::dryrun`.
+///
+/// # Safety
+///
+/// `options` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_options_set_dryrun(
+    options: *mut ubercopy_options,
+    value: bool,
+) {
+    update_options(options, |o| o.dryrun(value))
+}
+
+/// See `SyncOptions::force`.
+///
+/// # Safety
+///
+/// `options` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_options_set_force(
+    options: *mut ubercopy_options,
+    value: bool,
+) {
+    update_options(options, |o| o.force(value))
+}
+
+/// See `SyncOptions::verify_copy`.
+///
+/// # Safety
+///
+/// `options` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_options_set_verify_copy(
+    options: *mut ubercopy_options,
+    value: bool,
+) {
+    update_options(options, |o| o.verify_copy(value))
+}
+
+/// See `SyncOptions::keep_going`.
+///
+/// # Safety
+///
+/// `options` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_options_set_keep_going(
+    options: *mut ubercopy_options,
+    value: bool,
+) {
+    update_options(options, |o| o.keep_going(value))
+}
+
+/// See `SyncOptions::threads`.
+///
+/// # Safety
+///
+/// `options` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_options_set_threads(
+    options: *mut ubercopy_options,
+    value: usize,
+) {
+    update_options(options, |o| o.threads(value))
+}
+
+/// See `SyncOptions::retries`.
+///
+/// # Safety
+///
+/// `options` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_options_set_retries(
+    options: *mut ubercopy_options,
+    value: usize,
+) {
+    update_options(options, |o| o.retries(value))
+}
+
+/// See `SyncOptions::retry_delay`. The delay is in milliseconds.
+///
+/// # Safety
+///
+/// `options` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_options_set_retry_delay_ms(
+    options: *mut ubercopy_options,
+    value: u64,
+) {
+    update_options(options, |o| o.retry_delay(Duration::from_millis(value)))
+}
+
+/// Synchronizes the file system with the `next` manifest. `options` may be
+/// null to use the defaults. Always returns a report, which must be freed
+/// with `ubercopy_report_free`. If the sync panicked, its error code is
+/// `UBERCOPY_ERROR_PANIC`.
+///
+/// # Safety
+///
+/// `prev` and `next` must be valid manifests. `options` must be null or
+/// valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_sync(
+    prev: *const ubercopy_manifest,
+    next: *const ubercopy_manifest,
+    options: *const ubercopy_options,
+) -> *mut ubercopy_report {
+    run_sync(options, |options| sync(&(*prev).0, &(*next).0, options))
+}
+
+/// Like `ubercopy_sync`, but diffs against a state saved by an earlier sync
+/// and hands back the state of the destinations afterwards. Saving that with
+/// `ubercopy_manifest_save_state` and passing it as `prev` next time means
+/// that the next sync starts from what is really on disk.
+///
+/// If `state` is not null, it is set to the new state, which must be freed
+/// with `ubercopy_manifest_free`. It is set to null if the sync panicked, as
+/// the state is then unknown.
+///
+/// # Safety
+///
+/// `prev` must be a valid state and `next` a valid manifest. `options` must
+/// be null or valid. `state` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_sync_from_state(
+    prev: *const ubercopy_state,
+    next: *const ubercopy_manifest,
+    options: *const ubercopy_options,
+    state: *mut *mut ubercopy_manifest,
+) -> *mut ubercopy_report {
+    if !state.is_null() {
+        *state = ptr::null_mut();
+    }
+
+    run_sync(options, |options| {
+        let (manifest, result) =
+            sync_from_state(&(*prev).0, &(*next).0, options);
+
+        if !state.is_null() {
+            *state = Box::into_raw(Box::new(ubercopy_manifest(manifest)));
+        }
+
+        result
+    })
+}
+
+/// Runs a sync with the given options, recording its outcomes in a report.
+unsafe fn run_sync<F>(
+    options: *const ubercopy_options,
+    f: F,
+) -> *mut ubercopy_report
+where
+    F: FnOnce(&SyncOptions) -> Result<usize, Error>,
+{
+    let recorder = Arc::new(Recorder::default());
+
+    let options = if options.is_null() {
+        SyncOptions::new()
+    } else {
+        (*options).0.clone()
+    };
+
+    let options = options.observer(recorder.clone());
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&options)));
+
+    // The lock is poisoned if an observer panicked while holding it, but the
+    // outcomes recorded so far are still intact.
+    let outcomes = recorder
+        .outcomes
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .drain(..)
+        .collect();
+
+    let report = match result {
+        Ok(Ok(copied)) => ubercopy_report {
+            code: 0,
+            copied,
+            message: None,
+            outcomes,
+        },
+        Ok(Err(err)) => ubercopy_report {
+            code: error_code(err.kind()),
+            copied: 0,
+            message: Some(string_to_cstring(err.to_string())),
+            outcomes,
+        },
+        Err(payload) => ubercopy_report {
+            code: UBERCOPY_ERROR_PANIC,
+            copied: 0,
+            message: Some(string_to_cstring(panic_message(&*payload))),
+            outcomes,
+        },
+    };
+
+    Box::into_raw(Box::new(report))
+}
+
+/// Returns zero if the sync succeeded. Otherwise, returns a code identifying
+/// the kind of error. See `UBERCOPY_ERROR_*` in the header.
+///
+/// # Safety
+///
+/// `report` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_report_error(
+    report: *const ubercopy_report,
+) -> c_int {
+    catch(UBERCOPY_ERROR_PANIC, || (*report).code)
+}
+
+/// Returns the human-readable error message or null if the sync succeeded.
+///
+/// # Safety
+///
+/// `report` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_report_message(
+    report: *const ubercopy_report,
+) -> *const c_char {
+    catch(ptr::null(), || {
+        (*report)
+            .message
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Returns the number of files that were copied.
+///
+/// # Safety
+///
+/// `report` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_report_copied(
+    report: *const ubercopy_report,
+) -> usize {
+    catch(0, || (*report).copied)
+}
+
+/// Returns the number of per-file outcomes in the report.
+///
+/// # Safety
+///
+/// `report` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_report_len(
+    report: *const ubercopy_report,
+) -> usize {
+    catch(0, || (*report).outcomes.len())
+}
+
+/// Gets the outcome at `index`. Returns false if `index` is out of range.
+///
+/// # Safety
+///
+/// `report` must be valid and `outcome` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_report_get(
+    report: *const ubercopy_report,
+    index: usize,
+    outcome: *mut ubercopy_outcome,
+) -> bool {
+    fn as_ptr(s: &Option<CString>) -> *const c_char {
+        s.as_ref().map_or(ptr::null(), |s| s.as_ptr())
+    }
+
+    catch(false, || {
+        let report = &*report;
+
+        match report.outcomes.get(index) {
+            Some(o) => {
+                *outcome = ubercopy_outcome {
+                    event: o.event,
+                    phase: o.phase.into(),
+                    src: as_ptr(&o.src),
+                    dest: as_ptr(&o.dest),
+                    error: as_ptr(&o.error),
+                };
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Frees a report and all the strings it owns. Does nothing if `report` is
+/// null.
+///
+/// # Safety
+///
+/// `report` must be null or a report that has not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_report_free(report: *mut ubercopy_report) {
+    catch((), || {
+        if !report.is_null() {
+            drop(Box::from_raw(report));
+        }
+    })
+}
+
+/// Frees a string returned by this library. Does nothing if `s` is null.
+///
+/// # Safety
+///
+/// `s` must be null or a string returned by this library that has not
+/// already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ubercopy_string_free(s: *mut c_char) {
+    catch((), || {
+        if !s.is_null() {
+            drop(CString::from_raw(s));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use crate::faultfs::FaultFs;
+    use crate::memfs::MemoryFs;
+
+    #[test]
+    fn parse_error() {
+        let buf = b"foo\n";
+        let mut error = ptr::null_mut();
+
+        unsafe {
+            let manifest = ubercopy_manifest_parse(
+                buf.as_ptr(),
+                buf.len(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                &mut error,
+            );
+
+            assert!(manifest.is_null());
+            assert!(!error.is_null());
+            ubercopy_string_free(error);
+        }
+    }
+
+    #[test]
+    fn sync_report() {
+        let dir =
+            env::temp_dir().join(format!("ubercopy-capi-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+
+        let text = format!(
+            "{}\t{}\n{}\t{}\n",
+            dir.join("a.txt").display(),
+            dir.join("out/a.txt").display(),
+            dir.join("missing.txt").display(),
+            dir.join("out/missing.txt").display(),
+        );
+
+        unsafe {
+            let prev = ubercopy_manifest_new();
+            let next = ubercopy_manifest_parse(
+                text.as_ptr(),
+                text.len(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                ptr::null_mut(),
+            );
+            assert_eq!(ubercopy_manifest_len(next), 2);
+
+            let options = ubercopy_options_new();
+            ubercopy_options_set_retries(options, 0);
+
+            let report = ubercopy_sync(prev, next, options);
+            assert_eq!(
+                ubercopy_report_error(report),
+                error_code(ErrorKind::MissingSrcs)
+            );
+            assert!(!ubercopy_report_message(report).is_null());
+            assert_eq!(ubercopy_report_len(report), 1);
+
+            let mut outcome = ubercopy_outcome {
+                event: ubercopy_event::UBERCOPY_DELETED,
+                phase: ubercopy_phase::UBERCOPY_PHASE_DELETE,
+                src: ptr::null(),
+                dest: ptr::null(),
+                error: ptr::null(),
+            };
+            assert!(ubercopy_report_get(report, 0, &mut outcome));
+            assert_eq!(outcome.event, ubercopy_event::UBERCOPY_FAILED);
+            assert_eq!(outcome.phase, ubercopy_phase::UBERCOPY_PHASE_OUTDATED);
+            assert!(CStr::from_ptr(outcome.src)
+                .to_str()
+                .unwrap()
+                .ends_with("missing.txt"));
+            assert!(!outcome.error.is_null());
+            assert!(!ubercopy_report_get(report, 1, &mut outcome));
+
+            ubercopy_report_free(report);
+            ubercopy_options_free(options);
+            ubercopy_manifest_free(next);
+            ubercopy_manifest_free(prev);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_dest() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let buf = b"a.txt\tb.txt\n";
+        let dest = b"out\xff\0";
+
+        unsafe {
+            let manifest = ubercopy_manifest_parse(
+                buf.as_ptr(),
+                buf.len(),
+                ptr::null(),
+                dest.as_ptr() as *const c_char,
+                0,
+                ptr::null_mut(),
+            );
+            assert!(!manifest.is_null());
+
+            let op = &(*manifest).0.operations()[0];
+            assert_eq!(op.dest.as_os_str().as_bytes(), b"out\xff/b.txt");
+
+            ubercopy_manifest_free(manifest);
+        }
+    }
+
+    #[test]
+    fn src_and_dest_dirs() {
+        let src = string_to_cstring("src");
+        let dest = string_to_cstring("out");
+        let flags = UBERCOPY_SANDBOX_SRC | UBERCOPY_SANDBOX_DEST;
+
+        unsafe {
+            let buf = b"a.txt\tb.txt\n";
+            let manifest = ubercopy_manifest_parse(
+                buf.as_ptr(),
+                buf.len(),
+                src.as_ptr(),
+                dest.as_ptr(),
+                flags,
+                ptr::null_mut(),
+            );
+
+            let op = &(*manifest).0.operations()[0];
+            assert_eq!(op.src, Path::new("src/a.txt"));
+            assert_eq!(op.dest, Path::new("out/b.txt"));
+            ubercopy_manifest_free(manifest);
+
+            // Sources can't escape the source directory.
+            let buf = b"../a.txt\tb.txt\n";
+            let manifest = ubercopy_manifest_parse(
+                buf.as_ptr(),
+                buf.len(),
+                src.as_ptr(),
+                dest.as_ptr(),
+                flags,
+                ptr::null_mut(),
+            );
+            assert!(manifest.is_null());
+        }
+    }
+
+    #[test]
+    fn sync_from_saved_state() {
+        let memfs = Arc::new(MemoryFs::new());
+        memfs.write("a.txt", "a");
+        memfs.write("old.txt", "old");
+
+        let path =
+            env::temp_dir().join(format!("ubercopy-state-{}", process::id()));
+        let path = string_to_cstring(path.to_str().unwrap());
+
+        let options = SyncOptions::new().retries(0).filesystem(memfs.clone());
+        let options = Box::into_raw(Box::new(ubercopy_options(options)));
+
+        unsafe {
+            // A plain manifest works as the first state.
+            let buf = b"a.txt\told.txt\n";
+            let prev = ubercopy_state_parse(
+                buf.as_ptr(),
+                buf.len(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                ptr::null_mut(),
+            );
+            assert_eq!(ubercopy_state_len(prev), 1);
+
+            let buf = b"a.txt\tb.txt\n";
+            let next = ubercopy_manifest_parse(
+                buf.as_ptr(),
+                buf.len(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                ptr::null_mut(),
+            );
+
+            let mut state = ptr::null_mut();
+            let report =
+                ubercopy_sync_from_state(prev, next, options, &mut state);
+            assert_eq!(ubercopy_report_error(report), 0);
+            assert_eq!(ubercopy_report_copied(report), 1);
+            assert!(!memfs.exists("old.txt"));
+            assert_eq!(ubercopy_manifest_len(state), 1);
+
+            // The saved state can be read back for the next sync.
+            assert!(ubercopy_manifest_save_state(
+                state,
+                path.as_ptr(),
+                ptr::null_mut()
+            ));
+            let saved = fs::read(path.to_str().unwrap()).unwrap();
+            let reloaded = ubercopy_state_parse(
+                saved.as_ptr(),
+                saved.len(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                ptr::null_mut(),
+            );
+            assert_eq!(ubercopy_state_len(reloaded), 1);
+            assert_eq!((*reloaded).0.dests(), [Path::new("b.txt")]);
+
+            ubercopy_state_free(reloaded);
+            ubercopy_manifest_free(state);
+            ubercopy_report_free(report);
+            ubercopy_manifest_free(next);
+            ubercopy_state_free(prev);
+            ubercopy_options_free(options);
+        }
+
+        fs::remove_file(path.to_str().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn sync_panic() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("a.txt", "a");
+        fs.panic_on_copy("a.txt");
+
+        let buf = b"a.txt\tb.txt\n";
+        let options = SyncOptions::new().retries(0).threads(1).filesystem(fs);
+        let options = Box::into_raw(Box::new(ubercopy_options(options)));
+
+        unsafe {
+            let prev = ubercopy_manifest_new();
+            let next = ubercopy_manifest_parse(
+                buf.as_ptr(),
+                buf.len(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                ptr::null_mut(),
+            );
+
+            let report = ubercopy_sync(prev, next, options);
+            assert_eq!(ubercopy_report_error(report), UBERCOPY_ERROR_PANIC);
+
+            let message = CStr::from_ptr(ubercopy_report_message(report));
+            assert!(message.to_str().unwrap().starts_with("panicked: "));
+
+            ubercopy_report_free(report);
+            ubercopy_options_free(options);
+            ubercopy_manifest_free(next);
+            ubercopy_manifest_free(prev);
+        }
+    }
+}
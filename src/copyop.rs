@@ -18,50 +18,265 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use std::cmp::Ordering;
 use std::fmt;
 use std::io;
 use std::time::Duration;
 
+use crate::filesystem::{FileSystem, FileType, Metadata};
 use crate::util;
 
-use log;
+/// What a copy operation puts at its destination.
+#[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum OpKind {
+    /// A copy of the source file.
+    #[default]
+    Copy,
+
+    /// A symbolic link. The source is the target of the link, exactly as it
+    /// should be stored. It is never read.
+    Symlink,
+
+    /// A hard link to the source, which may be another destination. Links
+    /// are made after everything else has been copied.
+    Hardlink,
+}
 
 /// A copy operation.
-#[derive(Ord, PartialOrd, Eq, PartialEq, Debug)]
+///
+/// Two operations are equal if they put the same source at the same
+/// destination in the same way. The hash, tags and mode are not compared, so
+/// that a manifest can tell entries that would be merged apart from ones that
+/// disagree on those. Operations are ordered by destination first.
+#[derive(Clone, Debug)]
 pub struct CopyOp {
     pub src: PathBuf,
     pub dest: PathBuf,
+    pub kind: OpKind,
+
+    /// The expected hash of the source file, as given by the generator. This
+    /// is carried along for the generator's benefit and is not checked.
+    pub hash: Option<String>,
+
+    /// Free-form labels given by the generator.
+    pub tags: Vec<String>,
+
+    /// The permission bits to give the destination. If `None`, they are
+    /// copied from the source.
+    pub mode: Option<u32>,
+}
+
+impl CopyOp {
+    fn key(&self) -> (&Path, &Path, OpKind) {
+        (&self.dest, &self.src, self.kind)
+    }
 }
 
+impl Ord for CopyOp {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl PartialOrd for CopyOp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for CopyOp {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for CopyOp {}
+
 impl fmt::Display for CopyOp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
             "\"{}\" -> \"{}\"",
-            self.src.to_str().unwrap(),
-            self.dest.to_str().unwrap()
+            self.src.display(),
+            self.dest.display()
         )
     }
 }
 
+/// Returns `true` if both are known to be the same file, as with hard links.
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    a.id.is_some() && a.id == b.id
+}
+
 impl CopyOp {
     pub fn new(from: PathBuf, to: PathBuf) -> CopyOp {
         CopyOp {
             src: from,
             dest: to,
+            kind: OpKind::Copy,
+            hash: None,
+            tags: Vec::new(),
+            mode: None,
         }
     }
 
     /// Copies the source file to the given destination. It is expected that the
     /// destination directory already exists.
+    ///
+    /// For a symlink or a hard link, any existing destination is replaced
+    /// with a link to the source. A hard link that would cross devices is
+    /// copied instead. A copy replaces a link at the destination rather than
+    /// writing through it.
+    ///
+    /// If the operation has a mode, it is applied afterwards. When only the
+    /// mode is out of date, the contents are not copied again and `0` is
+    /// returned.
+    ///
+    /// If given, `progress` is called with the number of bytes written so far
+    /// while the contents are being copied.
     pub fn copy(
         &self,
+        fs: &dyn FileSystem,
         retries: usize,
         retry_delay: Duration,
+        progress: Option<&mut dyn FnMut(u64)>,
     ) -> io::Result<u64> {
-        util::copy_retry(&self.src, &self.dest, retries, retry_delay)
+        if self.kind == OpKind::Symlink {
+            util::remove_file_retry(fs, &self.dest, retries, retry_delay)?;
+            fs.symlink(&self.src, &self.dest)?;
+            return Ok(0);
+        }
+
+        if self.kind == OpKind::Hardlink {
+            util::remove_file_retry(fs, &self.dest, retries, retry_delay)?;
+
+            return match fs.hard_link(&self.src, &self.dest) {
+                Ok(()) => Ok(0),
+                Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                    log::warn!(
+                        "{}: Can't link across devices, copying instead",
+                        self
+                    );
+                    util::copy_retry(
+                        fs,
+                        &self.src,
+                        &self.dest,
+                        retries,
+                        retry_delay,
+                        progress,
+                    )
+                }
+                Err(err) => Err(err),
+            };
+        }
+
+        // Copying through a symlink would overwrite its target, so the link
+        // is removed first.
+        let b = fs.symlink_metadata(&self.dest).ok();
+
+        if b.as_ref().is_some_and(|b| b.file_type == FileType::Symlink) {
+            util::remove_file_retry(fs, &self.dest, retries, retry_delay)?;
+        }
+
+        let mode = match self.mode {
+            Some(mode) => mode,
+            None => {
+                return util::copy_retry(
+                    fs,
+                    &self.src,
+                    &self.dest,
+                    retries,
+                    retry_delay,
+                    progress,
+                );
+            }
+        };
+
+        let a = util::metadata_retry(fs, &self.src, retries, retry_delay)?;
+
+        let copied = match b {
+            Some(ref b) if self.same_contents(&a, b) && !same_file(&a, b) => 0,
+            _ => util::copy_retry(
+                fs,
+                &self.src,
+                &self.dest,
+                retries,
+                retry_delay,
+                progress,
+            )?,
+        };
+
+        fs.set_mode(&self.dest, mode)?;
+
+        Ok(copied)
+    }
+
+    fn is_linked(
+        &self,
+        fs: &dyn FileSystem,
+        retries: usize,
+        retry_delay: Duration,
+    ) -> io::Result<bool> {
+        let a = match util::metadata_retry(fs, &self.src, retries, retry_delay)
+        {
+            Ok(a) => a,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(false)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let b = match fs.metadata(&self.dest) {
+            Ok(b) => b,
+            Err(_) => return Ok(false),
+        };
+
+        match (a.id, b.id) {
+            (Some(a_id), Some(b_id)) if a_id == b_id => Ok(true),
+
+            // The file was copied because it is on a different device.
+            (Some((a_dev, _)), Some((b_dev, _))) if a_dev != b_dev => {
+                Ok(self.same_contents(&a, &b))
+            }
+
+            (Some(_), Some(_)) => {
+                log::trace!("{}: id {:?} != {:?}", self, a.id, b.id);
+                Ok(false)
+            }
+
+            // Without file ids, the best that can be done is to compare the
+            // contents.
+            _ => Ok(self.same_contents(&a, &b)),
+        }
+    }
+
+    /// Returns `true` if the destination appears to have the same contents as
+    /// the source.
+    fn same_contents(&self, a: &Metadata, b: &Metadata) -> bool {
+        if a.len != b.len {
+            log::trace!("{}: length {} != {}", self, a.len, b.len);
+            false
+        } else if a.file_type != b.file_type {
+            log::trace!(
+                "{}: file_type {:?} != {:?}",
+                self,
+                a.file_type,
+                b.file_type
+            );
+            false
+        } else if a.modified != b.modified {
+            log::trace!(
+                "{}: modified {:?} != {:?}",
+                self,
+                a.modified,
+                b.modified
+            );
+            false
+        } else {
+            true
+        }
     }
 
     /// Returns `true` if this copy operation is "complete". That is, if the
@@ -69,13 +284,36 @@ impl CopyOp {
     /// operation *cannot* complete if attempted. That is, if the source does
     /// not exist or we do not have permissions for it. Similarly, if both the
     /// source and destinations are both files or both directories.
+    ///
+    /// A symlink is complete if the destination is a link to the same target.
+    /// A hard link is complete if the destination is the same file as the
+    /// source. As the source may be another destination, it is not an error
+    /// for it to be missing.
     pub fn is_complete(
         &self,
+        fs: &dyn FileSystem,
         retries: usize,
         retry_delay: Duration,
     ) -> io::Result<bool> {
-        let a = util::metadata_retry(&self.src, retries, retry_delay)?;
-        let b = util::metadata_retry(&self.dest, retries, retry_delay);
+        if self.kind == OpKind::Symlink {
+            return Ok(match fs.read_link(&self.dest) {
+                Ok(target) if target == self.src => true,
+                Ok(target) => {
+                    log::trace!("{}: target {:?}", self, target);
+                    false
+                }
+                Err(_) => false,
+            });
+        }
+
+        if self.kind == OpKind::Hardlink {
+            return self.is_linked(fs, retries, retry_delay);
+        }
+
+        let a = util::metadata_retry(fs, &self.src, retries, retry_delay)?;
+        // A symlink at the destination is not a copy, even if its target
+        // looks like one.
+        let b = fs.symlink_metadata(&self.dest);
 
         if b.is_err() {
             // The destination file probably doesn't exist. The copy needs to
@@ -85,33 +323,36 @@ impl CopyOp {
 
         let b = b.unwrap();
 
+        // A destination that is still a hard link to the source has to be
+        // replaced with a copy.
+        if same_file(&a, &b) {
+            log::trace!("{}: destination is linked to the source", self);
+            return Ok(false);
+        }
+
         // All of these must be the same in order for the copy operation to be
-        // "complete".
-        if a.len() != b.len() {
-            log::trace!("{}: length {} != {}", self, a.len(), b.len());
+        // "complete". An explicit mode takes the place of the source's
+        // readonly flag.
+        if !self.same_contents(&a, &b) {
             Ok(false)
-        } else if a.file_type() != b.file_type() {
-            log::trace!(
-                "{}: file_type {:?} != {:?}",
-                self,
-                a.file_type(),
-                b.file_type()
-            );
-            Ok(false)
-        } else if a.modified().unwrap() != b.modified().unwrap() {
-            log::trace!(
-                "{}: modified {:?} != {:?}",
-                self,
-                a.modified().unwrap(),
-                b.modified().unwrap()
-            );
-            Ok(false)
-        } else if a.permissions().readonly() != b.permissions().readonly() {
+        } else if let Some(mode) = self.mode {
+            let complete = if cfg!(unix) {
+                b.mode == mode
+            } else {
+                b.readonly == (mode & 0o200 == 0)
+            };
+
+            if !complete {
+                log::trace!("{}: mode {:o} != {:o}", self, b.mode, mode);
+            }
+
+            Ok(complete)
+        } else if a.readonly != b.readonly {
             log::trace!(
                 "{}: readonly {:?} != {:?}",
                 self,
-                a.permissions().readonly(),
-                b.permissions().readonly()
+                a.readonly,
+                b.readonly
             );
             Ok(false)
         } else {
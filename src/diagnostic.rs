@@ -0,0 +1,152 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::ops::Deref;
+use std::path::PathBuf;
+
+/// A problem found while parsing a manifest.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// The manifest that the problem is in, if it was read from a file.
+    pub file: Option<PathBuf>,
+
+    /// The line number, starting at 1. In a NUL-delimited manifest, this is
+    /// the number of the entry instead. This is 0 if the problem is with the
+    /// manifest as a whole, like when it can't be opened.
+    pub line: usize,
+
+    /// The column in bytes, starting at 1. In a NUL-delimited manifest, this is
+    /// 1 for the source and 2 for the destination.
+    pub column: usize,
+
+    /// The offending text. Anything that isn't valid UTF-8 is replaced.
+    pub text: String,
+
+    /// What is wrong with it.
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file.display())?;
+        }
+
+        if self.line > 0 {
+            write!(f, "{}:{}:", self.line, self.column)?;
+        }
+
+        if self.file.is_some() || self.line > 0 {
+            write!(f, " ")?;
+        }
+
+        write!(f, "{}", self.message)?;
+
+        if !self.text.is_empty() {
+            write!(f, "\n    {}", self.text)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// All of the problems found while parsing a manifest. There is always at
+/// least one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub(crate) fn new(diagnostics: Vec<Diagnostic>) -> Self {
+        debug_assert!(!diagnostics.is_empty());
+        Diagnostics(diagnostics)
+    }
+
+    pub fn into_vec(self) -> Vec<Diagnostic> {
+        self.0
+    }
+}
+
+impl Deref for Diagnostics {
+    type Target = [Diagnostic];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Diagnostics(vec![diagnostic])
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{}", diagnostic)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StdError for Diagnostics {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let diagnostic = Diagnostic {
+            file: Some(PathBuf::from("manifest")),
+            line: 3,
+            column: 5,
+            text: "../a".into(),
+            message: "source path is not sandboxed".into(),
+        };
+
+        assert_eq!(
+            diagnostic.to_string(),
+            "manifest:3:5: source path is not sandboxed\n    ../a"
+        );
+
+        let diagnostics = Diagnostics::new(vec![
+            diagnostic.clone(),
+            Diagnostic {
+                file: None,
+                text: String::new(),
+                ..diagnostic
+            },
+        ]);
+
+        assert_eq!(
+            diagnostics.to_string(),
+            "manifest:3:5: source path is not sandboxed\n    ../a\n\
+             3:5: source path is not sandboxed"
+        );
+    }
+}
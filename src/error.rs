@@ -21,68 +21,147 @@
 use std::error::Error as StdError;
 use std::fmt;
 use std::io;
-use std::path::Path;
+use std::path::PathBuf;
 
 use crate::copyop::CopyOp;
 
+/// A machine-readable classification of an [`Error`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    Overlap,
+    Duplicates,
+    MissingSrcs,
+    CreateDirs,
+    Delete,
+    DeleteDirs,
+    Copy,
+    VerifyIncomplete,
+    VerifyErrors,
+    Cancelled,
+    Multiple,
+}
+
+impl ErrorKind {
+    /// A short, human-readable summary of this kind of error.
+    pub fn summary(self) -> &'static str {
+        match self {
+            ErrorKind::Overlap => "Overlapping sources and destinations",
+            ErrorKind::Duplicates => "Duplicate destinations",
+            ErrorKind::MissingSrcs => {
+                "Error finding out-of-date copy operations"
+            }
+            ErrorKind::CreateDirs => "Failed to create destination directories",
+            ErrorKind::Delete => "Failed to delete the following files",
+            ErrorKind::DeleteDirs => {
+                "Failed to delete the following directories"
+            }
+            ErrorKind::Copy => "Failed to copy file(s)",
+            ErrorKind::VerifyIncomplete => "Verification check failed",
+            ErrorKind::VerifyErrors => {
+                "Failed trying to perform verification check"
+            }
+            ErrorKind::Cancelled => "Sync was cancelled",
+            ErrorKind::Multiple => "Multiple phases failed",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.summary())
+    }
+}
+
 #[derive(Debug)]
-pub enum Error<'a> {
+pub enum Error {
     /// There are one or more paths that are common to both the source and
     /// destinations in the *next* manifest. Since source files can be copied
     /// to corresponding destinations in any order, this indicates a race
     /// condition.
-    Overlap(Vec<&'a Path>),
+    Overlap(Vec<PathBuf>),
 
     /// There are one or more paths that are duplicated in the destinations of
     /// the *next* manifest.
-    Duplicates(Vec<(&'a Path, usize)>),
+    Duplicates(Vec<(PathBuf, usize)>),
 
     /// There are one or more missing source files in the *next* manifest.
     /// Obviously, we can't copy what doesn't exist.
-    MissingSrcs(Vec<(&'a CopyOp, io::Error)>),
+    MissingSrcs(Vec<(CopyOp, io::Error)>),
 
     /// Some directories failed to get created.
-    CreateDirs(Vec<(&'a Path, io::Error)>),
+    CreateDirs(Vec<(PathBuf, io::Error)>),
 
     /// There are one or more files that failed to get deleted.
-    Delete(Vec<(&'a Path, io::Error)>),
+    Delete(Vec<(PathBuf, io::Error)>),
 
     /// There are one or more directories that failed to get deleted.
-    DeleteDirs(Vec<(&'a Path, io::Error)>),
+    DeleteDirs(Vec<(PathBuf, io::Error)>),
 
     /// There are one or more files that failed to get copied.
-    Copy(Vec<(&'a CopyOp, io::Error)>),
+    Copy(Vec<(CopyOp, io::Error)>),
 
     /// There are outdated copy operations after the copy. This should never
     /// happen and indicates a bug in Ubercopy.
-    VerifyIncomplete(Vec<&'a CopyOp>),
+    VerifyIncomplete(Vec<CopyOp>),
 
     /// There were failures when trying to determine outdated copy operations.
     /// This can happen if a source file was removed just after it was copied,
     /// but before we did the sanity check. This indicates a race condition
     /// with some other process.
-    VerifyErrors(Vec<(&'a CopyOp, io::Error)>),
+    VerifyErrors(Vec<(CopyOp, io::Error)>),
+
+    /// The sync was cancelled before it finished. Some operations may have
+    /// completed.
+    Cancelled,
+
+    /// More than one phase failed. This only happens when the sync is told to
+    /// keep going after a failure. The errors are in the order they occurred.
+    Multiple(Vec<Error>),
 }
 
-impl<'a> StdError for Error<'a> {
-    fn description(&self) -> &str {
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
         match *self {
-            Error::Overlap(_) => "Overlapping sources and destinations",
-            Error::Duplicates(_) => "Duplicate destinations",
-            Error::MissingSrcs(_) => {
-                "Error finding out-of-date copy operations"
+            Error::Overlap(_) => ErrorKind::Overlap,
+            Error::Duplicates(_) => ErrorKind::Duplicates,
+            Error::MissingSrcs(_) => ErrorKind::MissingSrcs,
+            Error::CreateDirs(_) => ErrorKind::CreateDirs,
+            Error::Delete(_) => ErrorKind::Delete,
+            Error::DeleteDirs(_) => ErrorKind::DeleteDirs,
+            Error::Copy(_) => ErrorKind::Copy,
+            Error::VerifyIncomplete(_) => ErrorKind::VerifyIncomplete,
+            Error::VerifyErrors(_) => ErrorKind::VerifyErrors,
+            Error::Cancelled => ErrorKind::Cancelled,
+            Error::Multiple(_) => ErrorKind::Multiple,
+        }
+    }
+}
+
+impl StdError for Error {
+    /// The first underlying I/O error, if there is one.
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        let err = match *self {
+            Error::Overlap(_)
+            | Error::Duplicates(_)
+            | Error::VerifyIncomplete(_)
+            | Error::Cancelled => None,
+            Error::MissingSrcs(ref errors)
+            | Error::Copy(ref errors)
+            | Error::VerifyErrors(ref errors) => {
+                errors.first().map(|(_, err)| err)
             }
-            Error::CreateDirs(_) => "Failed to create destination directories",
-            Error::Delete(_) => "Failed to delete the following files",
-            Error::DeleteDirs(_) => {
-                "Failed to delete the following directories"
+            Error::CreateDirs(ref errors)
+            | Error::Delete(ref errors)
+            | Error::DeleteDirs(ref errors) => {
+                errors.first().map(|(_, err)| err)
             }
-            Error::Copy(_) => "Failed to copy file(s)",
-            Error::VerifyIncomplete(_) => "Verification check failed",
-            Error::VerifyErrors(_) => {
-                "Failed trying to perform verification check"
+            Error::Multiple(ref errors) => {
+                return errors.iter().find_map(|err| err.source());
             }
-        }
+        };
+
+        err.map(|err| err as &(dyn StdError + 'static))
     }
 }
 
@@ -122,9 +201,13 @@ Error: Copy verification failed. The source file(s) listed above are either
        This indicates a race condition with some other process. Make sure
        nothing else is messing with these files during the copy.";
 
-impl<'a> fmt::Display for Error<'a> {
+const CANCELLED: &str = "\
+Error: The sync was cancelled before it finished. Some files may have already
+       been deleted or copied.";
+
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "{}:", self.description())?;
+        writeln!(f, "{}:", self.kind())?;
 
         match *self {
             Error::Overlap(ref overlap) => {
@@ -135,42 +218,42 @@ impl<'a> fmt::Display for Error<'a> {
                 writeln!(f, "{}", OVERLAP)
             }
             Error::Duplicates(ref duplicates) => {
-                for &(path, count) in duplicates {
+                for (path, count) in duplicates {
                     writeln!(f, " - {:?} ({} duplicates)", path, count)?;
                 }
 
                 writeln!(f, "{}", DUPLICATES)
             }
             Error::MissingSrcs(ref errors) => {
-                for &(op, ref err) in errors {
+                for (op, err) in errors {
                     writeln!(f, " - {:?}: {}", op.src, err)?;
                 }
 
                 writeln!(f, "{}", MISSING_SOURCES)
             }
             Error::CreateDirs(ref errors) => {
-                for &(dir, ref err) in errors {
+                for (dir, err) in errors {
                     writeln!(f, " - {:?}: {}", dir, err)?;
                 }
 
                 writeln!(f, "{}", CREATE_DIRS)
             }
             Error::Delete(ref failed) => {
-                for &(path, ref err) in failed {
+                for (path, err) in failed {
                     writeln!(f, " - {:?}: {}", path, err)?;
                 }
 
                 writeln!(f, "{}", DELETE)
             }
             Error::DeleteDirs(ref failed) => {
-                for &(dir, ref err) in failed {
+                for (dir, err) in failed {
                     writeln!(f, " - {:?}: {}", dir, err)?;
                 }
 
                 writeln!(f, "{}", DELETE_DIRS)
             }
             Error::Copy(ref errors) => {
-                for &(op, ref err) in errors {
+                for (op, err) in errors {
                     writeln!(f, " - {:?} ({})", op.src, err)?;
                 }
 
@@ -184,12 +267,57 @@ impl<'a> fmt::Display for Error<'a> {
                 writeln!(f, "{}", VERIFICATION_INCOMPLETE)
             }
             Error::VerifyErrors(ref errors) => {
-                for &(op, ref err) in errors {
+                for (op, err) in errors {
                     writeln!(f, " - {:?} ({})", op.src, err)?;
                 }
 
                 writeln!(f, "{}", VERIFICATION_ERRORS)
             }
+            Error::Cancelled => writeln!(f, "{}", CANCELLED),
+            Error::Multiple(ref errors) => {
+                for err in errors {
+                    writeln!(f)?;
+                    write!(f, "{}", err)?;
+                }
+
+                Ok(())
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_is_owned() {
+        fn assert_owned<T: StdError + Send + Sync + 'static>() {}
+        assert_owned::<Error>();
+    }
+
+    #[test]
+    fn kind_and_source() {
+        let err = Error::Delete(vec![(
+            PathBuf::from("foo"),
+            io::Error::new(io::ErrorKind::PermissionDenied, "denied"),
+        )]);
+
+        assert_eq!(err.kind(), ErrorKind::Delete);
+        assert_eq!(err.source().unwrap().to_string(), "denied");
+
+        let err = Error::Overlap(vec![PathBuf::from("foo")]);
+        assert_eq!(err.kind(), ErrorKind::Overlap);
+        assert!(err.source().is_none());
+
+        let err = Error::Multiple(vec![
+            Error::Cancelled,
+            Error::Delete(vec![(
+                PathBuf::from("foo"),
+                io::Error::new(io::ErrorKind::PermissionDenied, "denied"),
+            )]),
+        ]);
+        assert_eq!(err.kind(), ErrorKind::Multiple);
+        assert_eq!(err.source().unwrap().to_string(), "denied");
+    }
+}
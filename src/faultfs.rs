@@ -0,0 +1,358 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! A file system layer that injects failures into another file system. This
+//! is used to exercise the retry and failure handling of a sync.
+//!
+//! ```
+//! use std::io;
+//! use ubercopy::faultfs::{FaultFs, Operation};
+//! use ubercopy::MemoryFs;
+//!
+//! let fs = FaultFs::new(MemoryFs::new());
+//!
+//! // The first two copies to or from `dest/a` fail.
+//! fs.fail(Operation::Copy, "dest/a", 2, io::ErrorKind::TimedOut);
+//! ```
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::filesystem::{FileSystem, Metadata, RealFs, RemoveDir};
+
+/// The file system operations that faults can be injected into.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Operation {
+    Metadata,
+    SymlinkMetadata,
+    Copy,
+    RemoveFile,
+    RemoveDir,
+    SetMode,
+    HardLink,
+    ReadLink,
+    Symlink,
+    CreateDirAll,
+    ReadDir,
+}
+
+/// Direct access to the contents of files. Faults that leave only part of a
+/// file behind need this to write it, but a sync never does, so it isn't part
+/// of [`FileSystem`].
+pub trait Contents {
+    /// Reads the whole contents of a file.
+    fn read_contents(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Creates or truncates a file and fills it with `data`.
+    fn write_contents(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+}
+
+impl Contents for RealFs {
+    fn read_contents(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write_contents(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        fs::write(path, data)
+    }
+}
+
+#[derive(Debug)]
+enum Fault {
+    /// Fail the next `times` calls with an error of the given kind.
+    Fail {
+        op: Operation,
+        times: usize,
+        kind: io::ErrorKind,
+    },
+
+    /// The source of a copy is deleted just before it is copied.
+    Vanish,
+
+    /// Copies only write this many bytes, but still report success.
+    ShortWrite(u64),
+
+    /// Copies take at least this long.
+    Delay(Duration),
+
+    /// Copies panic, like a bug in the file system would.
+    Panic,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    faults: HashMap<PathBuf, Vec<Fault>>,
+    calls: HashMap<(Operation, PathBuf), usize>,
+}
+
+/// Wraps another file system and fails operations on specific paths as
+/// scripted. Every call is counted, whether or not it failed, so that tests
+/// can check how many times something was retried.
+#[derive(Debug)]
+pub struct FaultFs<F> {
+    inner: F,
+    state: Mutex<State>,
+}
+
+impl<F: FileSystem> FaultFs<F> {
+    pub fn new(inner: F) -> Self {
+        FaultFs {
+            inner,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The wrapped file system.
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Makes the next `times` calls of `op` that involve `path` fail with an
+    /// error of the given kind. For copies, `path` may be either the source
+    /// or the destination.
+    pub fn fail<P: Into<PathBuf>>(
+        &self,
+        op: Operation,
+        path: P,
+        times: usize,
+        kind: io::ErrorKind,
+    ) -> &Self {
+        self.add(path.into(), Fault::Fail { op, times, kind })
+    }
+
+    /// Deletes `src` as soon as a copy from it starts, as if another process
+    /// removed it. The copy then fails because the source is missing.
+    pub fn vanish_on_copy<P: Into<PathBuf>>(&self, src: P) -> &Self {
+        self.add(src.into(), Fault::Vanish)
+    }
+
+    /// Makes copies from `src` write only the first `len` bytes while still
+    /// reporting success.
+    pub fn short_write<P: Into<PathBuf>>(&self, src: P, len: u64) -> &Self {
+        self.add(src.into(), Fault::ShortWrite(len))
+    }
+
+    /// Makes copies from `src` take at least `delay`, as if they were going
+    /// over a slow network.
+    pub fn slow_copy<P: Into<PathBuf>>(
+        &self,
+        src: P,
+        delay: Duration,
+    ) -> &Self {
+        self.add(src.into(), Fault::Delay(delay))
+    }
+
+    /// Makes copies from `src` panic.
+    pub fn panic_on_copy<P: Into<PathBuf>>(&self, src: P) -> &Self {
+        self.add(src.into(), Fault::Panic)
+    }
+
+    /// Returns the number of times `op` has been called on `path`.
+    pub fn calls<P: AsRef<Path>>(&self, op: Operation, path: P) -> usize {
+        let state = self.state.lock().unwrap();
+        let key = (op, path.as_ref().to_path_buf());
+        state.calls.get(&key).cloned().unwrap_or(0)
+    }
+
+    fn add(&self, path: PathBuf, fault: Fault) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        state.faults.entry(path).or_default().push(fault);
+        self
+    }
+
+    /// Counts the call and returns an error if a failure has been scripted
+    /// for it.
+    fn check(&self, op: Operation, paths: &[&Path]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        for path in paths {
+            *state.calls.entry((op, path.to_path_buf())).or_insert(0) += 1;
+        }
+
+        for path in paths {
+            let faults = match state.faults.get_mut(*path) {
+                Some(faults) => faults,
+                None => continue,
+            };
+
+            for fault in faults.iter_mut() {
+                if let Fault::Fail {
+                    op: fault_op,
+                    ref mut times,
+                    kind,
+                } = *fault
+                {
+                    if fault_op == op && *times > 0 {
+                        *times -= 1;
+                        return Err(io::Error::new(
+                            kind,
+                            format!("injected {:?} fault on {:?}", op, path),
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn vanishes(&self, path: &Path) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .faults
+            .get(path)
+            .is_some_and(|f| f.iter().any(|f| matches!(f, Fault::Vanish)))
+    }
+
+    fn panics(&self, path: &Path) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .faults
+            .get(path)
+            .is_some_and(|f| f.iter().any(|f| matches!(f, Fault::Panic)))
+    }
+
+    fn delay(&self, path: &Path) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state.faults.get(path).and_then(|faults| {
+            faults.iter().find_map(|f| match *f {
+                Fault::Delay(delay) => Some(delay),
+                _ => None,
+            })
+        })
+    }
+
+    fn short_write_len(&self, path: &Path) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state.faults.get(path).and_then(|faults| {
+            faults.iter().find_map(|f| match *f {
+                Fault::ShortWrite(len) => Some(len),
+                _ => None,
+            })
+        })
+    }
+}
+
+impl<F: FileSystem + Contents> FaultFs<F> {
+    /// Copies only the first `len` bytes of `from` to `to`.
+    fn copy_part(
+        &self,
+        from: &Path,
+        to: &Path,
+        len: u64,
+        progress: Option<&mut dyn FnMut(u64)>,
+    ) -> io::Result<()> {
+        let data = self.inner.read_contents(from)?;
+        let part = &data[..cmp::min(len, data.len() as u64) as usize];
+        self.inner.write_contents(to, part)?;
+
+        if let Some(progress) = progress {
+            progress(part.len() as u64);
+        }
+
+        Ok(())
+    }
+}
+
+impl<F: FileSystem + Contents> FileSystem for FaultFs<F> {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.check(Operation::Metadata, &[path])?;
+        self.inner.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.check(Operation::SymlinkMetadata, &[path])?;
+        self.inner.symlink_metadata(path)
+    }
+
+    fn copy(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: Option<&mut dyn FnMut(u64)>,
+    ) -> io::Result<u64> {
+        self.check(Operation::Copy, &[from, to])?;
+
+        if let Some(delay) = self.delay(from) {
+            thread::sleep(delay);
+        }
+
+        if self.panics(from) {
+            panic!("injected panic on {:?}", from);
+        }
+
+        if self.vanishes(from) {
+            self.inner.remove_file(from)?;
+        }
+
+        if let Some(len) = self.short_write_len(from) {
+            self.copy_part(from, to, len, progress)?;
+            return self.inner.metadata(from).map(|m| m.len);
+        }
+
+        self.inner.copy(from, to, progress)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.check(Operation::RemoveFile, &[path])?;
+        self.inner.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<RemoveDir> {
+        self.check(Operation::RemoveDir, &[path])?;
+        self.inner.remove_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check(Operation::CreateDirAll, &[path])?;
+        self.inner.create_dir_all(path)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.check(Operation::SetMode, &[path])?;
+        self.inner.set_mode(path, mode)
+    }
+
+    fn hard_link(&self, src: &Path, dest: &Path) -> io::Result<()> {
+        self.check(Operation::HardLink, &[src, dest])?;
+        self.inner.hard_link(src, dest)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.check(Operation::ReadLink, &[path])?;
+        self.inner.read_link(path)
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        self.check(Operation::Symlink, &[path])?;
+        self.inner.symlink(target, path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.check(Operation::ReadDir, &[path])?;
+        self.inner.read_dir(path)
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Abstracts the file system operations that a sync needs so that they can
+//! be swapped out. [`RealFs`] uses the operating system and is the default.
+//! [`MemoryFs`](crate::memfs::MemoryFs) keeps everything in memory, which is
+//! useful for testing.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::util;
+
+/// The type of a file system entry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// What happened when removing a directory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RemoveDir {
+    /// The directory was removed.
+    Removed,
+
+    /// The directory didn't exist.
+    Missing,
+
+    /// The directory wasn't removed because it isn't empty.
+    NotEmpty,
+}
+
+/// The subset of file metadata that is used to decide whether or not a copy
+/// is up-to-date.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub len: u64,
+    pub modified: SystemTime,
+    pub readonly: bool,
+
+    /// The permission bits. Outside of Unix, these are made up from the
+    /// readonly flag.
+    pub mode: u32,
+
+    /// The device and inode numbers, which are the same for all hard links to
+    /// a file. These are only available on Unix.
+    pub id: Option<(u64, u64)>,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::File
+    }
+}
+
+impl From<fs::Metadata> for Metadata {
+    fn from(m: fs::Metadata) -> Self {
+        let file_type = if m.is_dir() {
+            FileType::Dir
+        } else if m.file_type().is_symlink() {
+            FileType::Symlink
+        } else {
+            FileType::File
+        };
+
+        Metadata {
+            file_type,
+            len: m.len(),
+            // This is only unavailable on platforms that we don't support.
+            modified: m.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            readonly: m.permissions().readonly(),
+            mode: util::mode(&m),
+            id: util::file_id(&m),
+        }
+    }
+}
+
+/// The file system operations used by a sync. Implementations must be
+/// thread-safe as they are used from many threads at once.
+pub trait FileSystem: Send + Sync {
+    /// Gets the metadata for a path, following symlinks.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Gets the metadata for a path without following symlinks.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Copies the contents, permissions and timestamps of `from` to `to`.
+    /// Returns the number of bytes copied. The parent directory of `to` must
+    /// already exist.
+    ///
+    /// An existing file at `to` is replaced rather than written to, so other
+    /// hard links to it are left alone. If the copy fails, `to` is left as it
+    /// was.
+    ///
+    /// If given, `progress` is called with the number of bytes written so far
+    /// as the copy goes. Without it, the copy is free to use faster ways of
+    /// copying that can't report progress.
+    fn copy(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: Option<&mut dyn FnMut(u64)>,
+    ) -> io::Result<u64>;
+
+    /// Removes a file. It is not an error if the file doesn't exist.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Removes an empty directory. It is not an error if the directory isn't
+    /// empty or doesn't exist.
+    fn remove_dir(&self, path: &Path) -> io::Result<RemoveDir>;
+
+    /// Sets the permission bits of a file. Outside of Unix, only the owner's
+    /// write bit is used to set the readonly flag.
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    /// Creates a hard link at `dest` to the file at `src`.
+    fn hard_link(&self, src: &Path, dest: &Path) -> io::Result<()>;
+
+    /// Returns the target of a symbolic link.
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Creates a symbolic link at `path` that points to `target`.
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()>;
+
+    /// Creates a directory and all of its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Lists the entries of a directory. Returns their full paths in no
+    /// particular order.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+}
+
+/// The real file system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFs;
+
+impl FileSystem for RealFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(Metadata::from)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path).map(Metadata::from)
+    }
+
+    fn copy(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: Option<&mut dyn FnMut(u64)>,
+    ) -> io::Result<u64> {
+        util::copy(from, to, progress)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        util::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<RemoveDir> {
+        util::remove_dir(path)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        util::set_mode(path, mode)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn hard_link(&self, src: &Path, dest: &Path) -> io::Result<()> {
+        fs::hard_link(src, dest)
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        util::symlink(target, path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! The optional header at the top of a manifest. It records which revision of
+//! the format a manifest is written in and how it was generated. Each field is
+//! on a line of its own:
+//!
+//! ```text
+//! #% version: 1
+//! #% generator: ["python", "generate.py"]
+//! #% cwd: /home/me/project
+//! #% src: build
+//! #% dest: out
+//! #% timestamp: 1760572800
+//! ```
+//!
+//! The header is the block of `#% key: value` lines at the very start of a
+//! manifest, and the version must come first. Any other line starting with
+//! `#%` is an ordinary comment. Since header lines start with `#`, older
+//! versions of Ubercopy see them as comments too.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The newest revision of the manifest format that can be read.
+pub const VERSION: u32 = 1;
+
+/// The prefix of a header line.
+pub(crate) const PREFIX: &str = "#%";
+
+/// Returns the field on a line, without the prefix, if the line has the
+/// form of a header field. Other lines starting with the prefix are comments.
+pub(crate) fn header_field(line: &[u8]) -> Option<&[u8]> {
+    let field = line.strip_prefix(PREFIX.as_bytes())?;
+    let key = field.trim_ascii_start();
+    let len = key
+        .iter()
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == b'-' || **c == b'_')
+        .count();
+
+    if len > 0 && key[len..].trim_ascii_start().starts_with(b":") {
+        Some(field)
+    } else {
+        None
+    }
+}
+
+/// Describes where a manifest came from.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Header {
+    /// The revision of the manifest format.
+    pub version: u32,
+
+    /// The program that generated the manifest, followed by its arguments.
+    pub generator: Vec<String>,
+
+    /// The directory that the generator was run in.
+    pub cwd: Option<PathBuf>,
+
+    /// The directory that source paths are relative to.
+    pub src: Option<PathBuf>,
+
+    /// The directory that destination paths are relative to.
+    pub dest: Option<PathBuf>,
+
+    /// When the manifest was generated, in seconds since the Unix epoch.
+    pub timestamp: Option<u64>,
+}
+
+impl Header {
+    /// Creates a header for the current version, stamped with the current
+    /// time.
+    pub fn new() -> Self {
+        Header {
+            version: VERSION,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs()),
+            ..Header::default()
+        }
+    }
+
+    /// Writes out the header lines.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "{} version: {}", PREFIX, self.version)?;
+
+        if !self.generator.is_empty() {
+            let generator = serde_json::to_string(&self.generator)?;
+            writeln!(writer, "{} generator: {}", PREFIX, generator)?;
+        }
+
+        if let Some(ref cwd) = self.cwd {
+            writeln!(writer, "{} cwd: {}", PREFIX, cwd.display())?;
+        }
+
+        if let Some(ref src) = self.src {
+            writeln!(writer, "{} src: {}", PREFIX, src.display())?;
+        }
+
+        if let Some(ref dest) = self.dest {
+            writeln!(writer, "{} dest: {}", PREFIX, dest.display())?;
+        }
+
+        if let Some(timestamp) = self.timestamp {
+            writeln!(writer, "{} timestamp: {}", PREFIX, timestamp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the field on a header line, without the prefix, to the header
+    /// being parsed. The header is started by the version field. Returns a
+    /// description of the problem if the field isn't valid.
+    pub(crate) fn parse_field(
+        header: &mut Option<Header>,
+        text: &[u8],
+    ) -> Result<(), String> {
+        let text = str::from_utf8(text)
+            .map_err(|_| "Header field isn't valid UTF-8".to_string())?;
+
+        let (key, value) = text
+            .split_once(':')
+            .ok_or_else(|| "Expected key: value header field".to_string())?;
+
+        let (key, value) = (key.trim(), value.trim());
+
+        let header = match header {
+            Some(header) => header,
+            None if key == "version" => header.insert(Header::default()),
+            None => {
+                return Err("The header must start with a version".to_string())
+            }
+        };
+
+        match key {
+            "version" => {
+                let version = value
+                    .parse()
+                    .ok()
+                    .filter(|&version| version > 0)
+                    .ok_or_else(|| {
+                        format!("Invalid manifest version \"{}\"", value)
+                    })?;
+
+                if version > VERSION {
+                    return Err(format!(
+                        "Manifest version {} is newer than the supported \
+                         version {}",
+                        version, VERSION
+                    ));
+                }
+
+                header.version = version;
+            }
+            "generator" => {
+                header.generator =
+                    serde_json::from_str(value).map_err(|_| {
+                        "Expected the generator to be a list of strings"
+                            .to_string()
+                    })?;
+            }
+            "cwd" => header.cwd = Some(PathBuf::from(value)),
+            "src" => header.src = Some(PathBuf::from(value)),
+            "dest" => header.dest = Some(PathBuf::from(value)),
+            "timestamp" => {
+                header.timestamp =
+                    Some(value.parse().map_err(|_| {
+                        format!("Invalid timestamp \"{}\"", value)
+                    })?);
+            }
+            _ => return Err(format!("Unknown header field \"{}\"", key)),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Option<Header>, String> {
+        let mut header = None;
+
+        for line in text.lines() {
+            let field = line.strip_prefix(PREFIX).unwrap();
+            Header::parse_field(&mut header, field.as_bytes())?;
+        }
+
+        Ok(header)
+    }
+
+    #[test]
+    fn round_trip() {
+        let header = Header {
+            generator: vec!["python".into(), "gen erate.py".into()],
+            cwd: Some(PathBuf::from("/work")),
+            src: Some(PathBuf::from("build")),
+            dest: Some(PathBuf::from("out")),
+            ..Header::new()
+        };
+
+        let mut text = Vec::new();
+        header.write(&mut text).unwrap();
+
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("#% version: 1\n"));
+        assert_eq!(parse(&text).unwrap(), Some(header));
+
+        let header = parse("#% version: 1").unwrap().unwrap();
+        assert_eq!(header.timestamp, None);
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(header_field(b"#% version: 1"), Some(&b" version: 1"[..]));
+        assert_eq!(header_field(b"#%dest :out"), Some(&b"dest :out"[..]));
+        assert_eq!(header_field(b"#%%%"), None);
+        assert_eq!(header_field(b"#% note"), None);
+        assert_eq!(header_field(b"#% a note: with a colon"), None);
+        assert_eq!(header_field(b"# version: 1"), None);
+    }
+
+    #[test]
+    fn problems() {
+        for (text, message) in &[
+            ("#% dest: out", "The header must start with a version"),
+            ("#% version: 0", "Invalid manifest version \"0\""),
+            (
+                "#% version: 2",
+                "Manifest version 2 is newer than the supported version 1",
+            ),
+            ("#% version 1", "Expected key: value header field"),
+            (
+                "#% version: 1\n#% colour: red",
+                "Unknown header field \"colour\"",
+            ),
+            (
+                "#% version: 1\n#% generator: python",
+                "Expected the generator to be a list of strings",
+            ),
+            (
+                "#% version: 1\n#% timestamp: now",
+                "Invalid timestamp \"now\"",
+            ),
+        ] {
+            assert_eq!(parse(text).unwrap_err(), *message);
+        }
+    }
+}
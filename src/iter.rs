@@ -244,8 +244,8 @@ mod tests {
 
     #[test]
     fn changes_both_sides() {
-        let a = vec![1, 2, 3, 4, 6];
-        let b = vec![1, 3, 4, 5];
+        let a = [1, 2, 3, 4, 6];
+        let b = [1, 3, 4, 5];
 
         let mut c = a.iter().changes(b.iter());
         assert_eq!(c.next(), Some((&1, Change::None)));
@@ -267,7 +267,7 @@ mod tests {
 
     #[test]
     fn adjacent_case_1() {
-        let v = vec![1];
+        let v = [1];
         let mut adj = v.iter().adjacent();
         assert_eq!(adj.next(), Some((&1, 1)));
         assert_eq!(adj.next(), None);
@@ -275,7 +275,7 @@ mod tests {
 
     #[test]
     fn adjacent_case_2() {
-        let v = vec![1, 2, 2, 3, 4, 4, 4];
+        let v = [1, 2, 2, 3, 4, 4, 4];
 
         let mut adj = v.iter().adjacent();
         assert_eq!(adj.next(), Some((&1, 1)));
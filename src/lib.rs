@@ -0,0 +1,68 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Ubercopy synchronizes files as listed in a manifest.
+//!
+//! A manifest is a list of source and destination path pairs. Given the
+//! previous and the next manifest, [`sync`] deletes destinations that have
+//! been removed, copies files that are out of date, and optionally verifies
+//! the result afterwards.
+//!
+//! ```no_run
+//! use ubercopy::{sync, Manifest, ParseOptions, SyncOptions};
+//!
+//! let prev = Manifest::new();
+//! let next = Manifest::parse("manifest", &ParseOptions::new()).unwrap();
+//!
+//! let options = SyncOptions::new().threads(4).verify_copy(true);
+//! let copied = sync(&prev, &next, &options).unwrap();
+//!
+//! println!("Copied {} file(s)", copied);
+//! ```
+
+#[cfg(feature = "tokio")]
+pub mod async_sync;
+pub mod capi;
+pub mod copyop;
+pub mod diagnostic;
+pub mod error;
+pub mod faultfs;
+pub mod filesystem;
+pub mod header;
+mod iter;
+pub mod manifest;
+pub mod memfs;
+pub mod observer;
+pub mod parser;
+mod state;
+pub mod sync;
+mod util;
+mod vars;
+
+pub use crate::copyop::{CopyOp, OpKind};
+pub use crate::diagnostic::{Diagnostic, Diagnostics};
+pub use crate::error::{Error, ErrorKind};
+pub use crate::filesystem::{FileSystem, RealFs};
+pub use crate::header::Header;
+pub use crate::manifest::{Manifest, State};
+pub use crate::memfs::MemoryFs;
+pub use crate::observer::{Phase, SyncObserver};
+pub use crate::parser::{Format, ParseOptions};
+pub use crate::sync::{sync, sync_from_state, sync_with_state, SyncOptions};
@@ -20,37 +20,53 @@
 #[macro_use]
 extern crate clap;
 
-use duct;
-use log;
-use log4rs;
-
 mod args;
-mod copyop;
-mod error;
-mod iter;
-mod manifest;
-mod sync;
-mod util;
 
-use crate::args::Args;
-use crate::manifest::Manifest;
-use crate::sync::sync;
+use crate::args::{Args, Source};
+
+use ubercopy::{sync_from_state, Format, Header, Manifest, State};
 
 use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::BufReader;
+use std::io::{self, BufReader};
 use std::path::Path;
 use std::process::exit;
 use std::str::FromStr;
-use std::time::Duration;
 
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Config, Root};
 use log4rs::encode::pattern::PatternEncoder;
 
-fn generate_manifest<T, P>(program: T, args: &[String], path: P)
+/// The header that describes how the manifest was generated. NUL-delimited
+/// manifests can't have one.
+fn header(args: &Args) -> Option<Header> {
+    if args.format == Format::Null {
+        return None;
+    }
+
+    let generator = match args.source {
+        Source::Generator(ref program, ref program_args) => Some(program)
+            .into_iter()
+            .chain(program_args)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Some(Header {
+        generator,
+        cwd: env::current_dir().ok(),
+        src: Some(args.src.clone()).filter(|d| !d.as_os_str().is_empty()),
+        dest: Some(args.dest.clone()).filter(|d| !d.as_os_str().is_empty()),
+        ..Header::new()
+    })
+}
+
+/// Creates the manifest and writes out its header. The manifest itself gets
+/// written after that.
+fn create_manifest<P>(path: P, header: Option<&Header>) -> fs::File
 where
-    T: AsRef<str>,
     P: AsRef<Path>,
 {
     log::info!("Creating manifest {:?}", path.as_ref());
@@ -62,16 +78,27 @@ where
         exit(1);
     }
 
+    let mut f = f.unwrap();
+    if let Some(Err(err)) = header.map(|header| header.write(&mut f)) {
+        log::error!("Failed to write manifest header ({})", err);
+        exit(1);
+    }
+
+    f
+}
+
+fn generate_manifest<T>(program: T, args: &[OsString], f: fs::File)
+where
+    T: AsRef<OsStr>,
+{
     log::info!(
-        "Running process `{}` with arguments {:?} to generate manifest",
+        "Running process {:?} with arguments {:?} to generate manifest",
         program.as_ref(),
         args
     );
 
     // Run the command to produce the manifest.
-    let output = duct::cmd(program.as_ref(), args)
-        .stdout_file(f.unwrap())
-        .run();
+    let output = duct::cmd(program.as_ref(), args).stdout_file(f).run();
 
     if let Err(err) = output {
         log::error!("Failed to generate manifest. {}", err);
@@ -79,6 +106,13 @@ where
     }
 }
 
+fn copy_manifest<R: io::Read>(mut reader: R, mut f: fs::File) {
+    if let Err(err) = io::copy(&mut reader, &mut f) {
+        log::error!("Failed to copy manifest ({})", err);
+        exit(1);
+    }
+}
+
 fn main() {
     let log_level = match env::var("UBERCOPY_LOG") {
         Ok(val) => log::LevelFilter::from_str(val.as_str())
@@ -106,48 +140,76 @@ fn main() {
     path_next.push(".next");
     let path_next = Path::new(&path_next);
 
-    generate_manifest(args.program, &args.args, &path_next);
+    let header = header(&args);
 
-    // Previous manifest
+    match args.source {
+        Source::Generator(ref program, ref program_args) => {
+            let f = create_manifest(path_next, header.as_ref());
+            generate_manifest(program, program_args, f);
+        }
+        Source::File(ref path) => {
+            log::info!("Reading manifest from {:?}", path);
+
+            let reader = fs::File::open(path).unwrap_or_else(|err| {
+                log::error!("Failed to open manifest {:?} ({})", path, err);
+                exit(1);
+            });
+
+            let f = create_manifest(path_next, header.as_ref());
+            copy_manifest(reader, f);
+        }
+        Source::Stdin => {
+            log::info!("Reading manifest from stdin");
+
+            let f = create_manifest(path_next, header.as_ref());
+            copy_manifest(io::stdin().lock(), f);
+        }
+    }
+
+    let parse_options = args.parse_options();
+
+    // State left behind by the previous sync
     let prev = match fs::File::open(path_prev) {
-        Ok(f) => Manifest::parse_reader(
-            BufReader::new(f),
-            &args.dest,
-            args.sandbox_src,
-            args.sandbox_dest,
-        ),
-        Err(_) => Ok(Manifest::new()),
+        Ok(f) => State::parse(BufReader::new(f), &parse_options),
+        Err(_) => Ok(State::from(Manifest::new())),
     };
 
     if let Err(err) = prev {
-        println!("Error: Failed to parse manifest: {}", err);
+        println!("Error: Failed to parse manifest:\n{}", err);
         exit(1);
     }
 
+    // The manifest was copied next to the state, but its includes are still
+    // relative to where it came from.
+    let next_options = match args.source {
+        Source::Generator(..) => parse_options,
+        Source::File(ref path) => parse_options
+            .include_dir(path.parent().unwrap_or_else(|| Path::new(""))),
+        Source::Stdin => parse_options.include_dir(""),
+    };
+
     // Next manifest
-    let next = Manifest::parse(
-        &path_next,
-        &args.dest.as_path(),
-        args.sandbox_src,
-        args.sandbox_dest,
-    );
+    let next = Manifest::parse(path_next, &next_options);
 
     if let Err(err) = next {
-        println!("Error: Failed to parse manifest: {}", err);
+        println!("Error: Failed to parse manifest:\n{}", err);
         exit(1);
     }
 
     // Do the synchronization and handle errors.
-    match sync(
-        &prev.unwrap(),
-        &next.unwrap(),
-        args.dryrun,
-        args.force,
-        args.verify_copy,
-        args.threads,
-        args.retries,
-        Duration::from_secs(1),
-    ) {
+    let (state, result) =
+        sync_from_state(&prev.unwrap(), &next.unwrap(), &args.sync_options());
+
+    if !args.dryrun {
+        // Record what actually happened, even if the sync failed part way
+        // through, so that the next sync starts from what is really on disk.
+        if let Err(err) = state.save_state(path_prev) {
+            println!("Failed to save state to {:?}: {}", path_prev, err);
+            exit(1);
+        }
+    }
+
+    match result {
         Ok(copied) => {
             println!("Successfully copied {} file(s).", copied);
         }
@@ -158,13 +220,9 @@ fn main() {
     };
 
     if !args.dryrun {
-        // Replace previous manifest with next manifest if everything succeeds.
-        // This is an atomic way of saying that everything succeeded.
-        if let Err(err) = fs::rename(&path_next, path_prev) {
-            println!(
-                "Failed to rename {:?} to {:?}: {}",
-                path_next, path_prev, err
-            );
+        // The state now covers everything in the next manifest.
+        if let Err(err) = fs::remove_file(path_next) {
+            println!("Failed to remove {:?}: {}", path_next, err);
             exit(1);
         }
     }
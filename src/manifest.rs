@@ -20,122 +20,175 @@
 
 use scoped_pool::Pool;
 
-use crate::copyop::CopyOp;
-
-use std::fs::File;
-use std::io;
-use std::path::{Path, PathBuf};
+use crate::copyop::{CopyOp, OpKind};
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::header::Header;
+use crate::parser::{ParseOptions, Parser};
+use crate::state;
+use crate::sync::SyncOptions;
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
+use std::iter::FromIterator;
+use std::path::Path;
 use std::sync::mpsc::sync_channel;
-use std::time::Duration;
 
-use crate::util::PathExt;
+fn read_error(err: io::Error) -> Diagnostic {
+    Diagnostic {
+        file: None,
+        line: 0,
+        column: 0,
+        text: String::new(),
+        message: format!("Failed to read manifest: {}", err),
+    }
+}
+
+fn open_error(path: &Path, err: io::Error) -> Diagnostic {
+    Diagnostic {
+        file: Some(path.to_path_buf()),
+        line: 0,
+        column: 0,
+        text: String::new(),
+        message: format!("Failed to open manifest: {}", err),
+    }
+}
 
 /// Represents a manifest. A manifest is simply a sequence of copy operations.
+#[derive(Debug)]
 pub struct Manifest {
+    header: Option<Header>,
     operations: Vec<CopyOp>,
 }
 
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest::new()
+    }
+}
+
+impl FromIterator<CopyOp> for Manifest {
+    fn from_iter<I: IntoIterator<Item = CopyOp>>(iter: I) -> Self {
+        let mut operations: Vec<CopyOp> = iter.into_iter().collect();
+        operations.sort();
+        operations.dedup();
+        Manifest {
+            header: None,
+            operations,
+        }
+    }
+}
+
 impl Manifest {
     pub fn new() -> Manifest {
-        Manifest { operations: vec![] }
+        Manifest {
+            header: None,
+            operations: vec![],
+        }
+    }
+
+    /// Sets the header that describes where this manifest came from.
+    pub fn with_header(mut self, header: Option<Header>) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// The header of the manifest, if it had one.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
     }
 
-    pub fn parse_reader<R, P>(
+    /// Parses a manifest in the format given by the options. Except for the
+    /// NUL-delimited format, blank lines and lines starting with `#` are
+    /// ignored, apart from the header lines at the top. If there are any
+    /// problems with the manifest, all of them are returned.
+    pub fn parse_reader<R>(
         reader: R,
-        dest_dir: P,
-        sandbox_src: bool,
-        sandbox_dest: bool,
-    ) -> Result<Self, String>
+        options: &ParseOptions,
+    ) -> Result<Self, Diagnostics>
     where
         R: io::BufRead,
-        P: AsRef<Path>,
     {
-        let dest_dir = dest_dir.as_ref();
-
-        let mut operations: Vec<CopyOp> = Vec::new();
-
-        for (i, line) in reader.lines().enumerate() {
-            let line = line.unwrap();
-            let line = line.trim();
-
-            if line.is_empty() || line.starts_with('#') {
-                // Ignore blank lines and comments
-                continue;
-            }
-
-            let mut s = line.split('\t');
-
-            let src = s.next().ok_or_else(|| {
-                format!("Missing source file on line {}", i + 1)
-            })?;
-            let dest = s.next().ok_or_else(|| {
-                format!("Missing destination file on line {}", i + 1)
-            })?;
-
-            let src_path = Path::new(src).norm();
-
-            if sandbox_src && !src_path.is_sandboxed() {
-                return Err(format!(
-                    "source path {:?} is not sandboxed",
-                    src_path
-                ));
-            }
+        Manifest::parse_from(reader, options, None)
+    }
 
-            let dest_path = Path::new(dest).norm();
+    pub fn parse<P>(
+        path: P,
+        options: &ParseOptions,
+    ) -> Result<Self, Diagnostics>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let f = File::open(path).map_err(|err| open_error(path, err))?;
+        Manifest::parse_from(io::BufReader::new(f), options, Some(path))
+    }
 
-            if sandbox_dest && !dest_path.is_sandboxed() {
-                return Err(format!(
-                    "destination path {:?} is not sandboxed",
-                    dest_path
-                ));
-            }
+    fn parse_from<R>(
+        reader: R,
+        options: &ParseOptions,
+        file: Option<&Path>,
+    ) -> Result<Self, Diagnostics>
+    where
+        R: io::BufRead,
+    {
+        let mut parser = Parser::new(options, file);
+        parser.parse(reader);
+        let (header, operations) = parser.finish()?;
+        Ok(operations
+            .into_iter()
+            .collect::<Manifest>()
+            .with_header(header))
+    }
 
-            let dest_path = if dest_dir.is_empty() {
-                dest_path
-            } else {
-                let mut path = PathBuf::new();
-                path.push(dest_dir);
-                path.push(dest_path);
-                path.norm()
-            };
+    /// Parses the state saved by a previous sync. For compatibility, this also
+    /// accepts a plain manifest, in which case it is parsed with the given
+    /// options like with [`Manifest::parse_reader`].
+    pub fn parse_state<R>(
+        reader: R,
+        options: &ParseOptions,
+    ) -> Result<Self, Diagnostics>
+    where
+        R: BufRead,
+    {
+        State::parse(reader, options).map(State::into_manifest)
+    }
 
-            operations.push(CopyOp::new(src_path, dest_path));
-        }
+    /// Writes this manifest out as state that can be read back with
+    /// [`Manifest::parse_state`]. The state is saved in a compact binary
+    /// format that can be read back without parsing and sorting the paths
+    /// again.
+    pub fn write_state<W: Write>(&self, writer: W) -> io::Result<()> {
+        state::write(writer, self.header(), &self.operations)
+    }
 
-        // This vector needs to be sorted so that we can diff two manifests.
-        operations.sort();
+    /// Saves this manifest as state to the given path. The file is written to a
+    /// temporary path first and then renamed so that the state on disk is never
+    /// only half written.
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
 
-        // It is fine for a manifest to have duplicate copy operations. Remove
-        // them here so that we don't get errors about duplicate destinations.
-        operations.dedup();
+        let mut temp = path.as_os_str().to_os_string();
+        temp.push(".tmp");
+        let temp = Path::new(&temp);
 
-        Ok(Manifest { operations })
-    }
+        let f = File::create(temp)?;
+        self.write_state(io::BufWriter::new(&f))?;
+        f.sync_all()?;
 
-    pub fn parse<P>(
-        path: P,
-        dest: P,
-        sandbox_src: bool,
-        sandbox_dest: bool,
-    ) -> Result<Self, String>
-    where
-        P: AsRef<Path>,
-    {
-        let f = File::open(path).map_err(|e| e.to_string())?;
-        Manifest::parse_reader(
-            io::BufReader::new(f),
-            dest,
-            sandbox_src,
-            sandbox_dest,
-        )
+        fs::rename(temp, path)
     }
 
-    /// Returns a sorted list of all sources.
+    /// Returns a sorted list of all sources that are copied from. The sources
+    /// of links are left out, as they are never read while copying.
     pub fn srcs(&self) -> Vec<&Path> {
-        self.operations()
+        let mut srcs: Vec<&Path> = self
+            .operations()
             .iter()
+            .filter(|op| op.kind == OpKind::Copy)
             .map(|op| op.src.as_path())
-            .collect()
+            .collect();
+        srcs.sort();
+        srcs
     }
 
     /// Returns a sorted list of all destinations.
@@ -161,46 +214,498 @@ impl Manifest {
         &self,
         force: bool,
         pool: &Pool,
-        retries: usize,
-        retry_delay: Duration,
+        options: &SyncOptions,
     ) -> Result<Vec<&CopyOp>, Vec<(&CopyOp, io::Error)>> {
-        log::info!("Finding list of outdated copy operations");
+        let ops: Vec<&CopyOp> = self.operations.iter().collect();
+
+        let (result, errors) = outdated(&ops, force, pool, options);
+
+        if errors.is_empty() {
+            Ok(result)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Splits `ops` into the ones that are outdated and the ones that couldn't be
+/// checked. Unlike [`Manifest::outdated`], this returns both lists so that a
+/// failure to check one operation doesn't hold up the others.
+pub(crate) fn outdated<'a>(
+    ops: &[&'a CopyOp],
+    force: bool,
+    pool: &Pool,
+    options: &SyncOptions,
+) -> (Vec<&'a CopyOp>, Vec<(&'a CopyOp, io::Error)>) {
+    log::info!("Finding list of outdated copy operations");
+
+    if force {
+        // Assume all files need to be copied.
+        return (ops.to_vec(), Vec::new());
+    }
+
+    let (retries, retry_delay) = (options.retries, options.retry_delay);
+    let observer = options.observer.as_ref();
+    let fs = options.filesystem.as_ref();
+
+    let (tx, rx) = sync_channel(32);
+
+    let (errors, result) = pool.scoped(|scope| {
+        for &op in ops {
+            let tx = tx.clone();
+            scope.execute(move || {
+                let complete = options
+                    .check_cancelled()
+                    .and_then(|()| op.is_complete(fs, retries, retry_delay));
+
+                if let Ok(complete) = complete {
+                    observer.checked(op, !complete);
+                }
+
+                tx.send((op, complete)).unwrap();
+            });
+        }
 
-        if force {
-            // Assume all files need to be copied.
-            return Ok(self.operations.iter().collect());
+        // If a job panics, it never sends its result. Once every sender is
+        // gone, the loop ends early and the panic is propagated.
+        drop(tx);
+
+        let mut errors: Vec<(&CopyOp, io::Error)> = Vec::new();
+        let mut result: Vec<&CopyOp> = Vec::new();
+
+        for (op, complete) in rx.iter().take(ops.len()) {
+            match complete {
+                Ok(false) => result.push(op),
+                Ok(true) => {}
+                Err(err) => errors.push((op, err)),
+            };
+        }
+
+        (errors, result)
+    });
+
+    log::info!("Found {} outdated copy operations", result.len());
+
+    (result, errors)
+}
+
+/// The state left behind by a previous sync, as read by [`State::parse`].
+///
+/// Unlike with [`Manifest::parse_state`], the copy operations of a state
+/// saved in the binary format are not decoded up front. Its destinations are
+/// borrowed straight from the saved bytes, so [`sync_from_state`] can diff
+/// against millions of them without decoding a single copy operation.
+///
+/// [`sync_from_state`]: crate::sync::sync_from_state
+#[derive(Debug)]
+pub struct State {
+    repr: StateRepr,
+}
+
+#[derive(Debug)]
+enum StateRepr {
+    /// A state in the binary format and where its parts are.
+    Index(Vec<u8>, state::Layout),
+
+    /// A plain manifest, or a state in the binary format that isn't sorted.
+    Manifest(Manifest),
+}
+
+impl From<Manifest> for State {
+    fn from(manifest: Manifest) -> Self {
+        State {
+            repr: StateRepr::Manifest(manifest),
+        }
+    }
+}
+
+impl State {
+    /// Reads the state saved by a previous sync. For compatibility, this also
+    /// accepts a plain manifest, in which case it is parsed with the given
+    /// options like with [`Manifest::parse_reader`].
+    pub fn parse<R>(
+        mut reader: R,
+        options: &ParseOptions,
+    ) -> Result<Self, Diagnostics>
+    where
+        R: BufRead,
+    {
+        let mut data = Vec::new();
+
+        reader
+            .by_ref()
+            .take(state::MAGIC.len() as u64)
+            .read_to_end(&mut data)
+            .map_err(read_error)?;
+
+        if data != state::MAGIC {
+            return Manifest::parse_reader(
+                io::Cursor::new(data).chain(reader),
+                options,
+            )
+            .map(State::from);
         }
 
-        let (tx, rx) = sync_channel(32);
+        reader.read_to_end(&mut data).map_err(read_error)?;
 
-        let (errors, result) = pool.scoped(|scope| {
-            for op in &self.operations {
-                let tx = tx.clone();
-                scope.execute(move || {
-                    tx.send((op, op.is_complete(retries, retry_delay)))
-                        .unwrap();
-                });
+        let layout = state::validate(&data).map_err(|message| Diagnostic {
+            file: None,
+            line: 0,
+            column: 0,
+            text: String::new(),
+            message: format!("Invalid state: {}", message),
+        })?;
+
+        // The operations are saved sorted, so they normally don't need to be
+        // sorted again.
+        let sorted = layout.index(&data).is_sorted();
+
+        let state = State {
+            repr: StateRepr::Index(data, layout),
+        };
+
+        if sorted {
+            return Ok(state);
+        }
+
+        let Manifest { header, operations } = state.into_manifest();
+
+        Ok(State::from(
+            operations
+                .into_iter()
+                .collect::<Manifest>()
+                .with_header(header),
+        ))
+    }
+
+    /// The header of the manifest that the state was made from.
+    pub fn header(&self) -> Option<&Header> {
+        match self.repr {
+            StateRepr::Index(_, ref layout) => layout.header.as_ref(),
+            StateRepr::Manifest(ref manifest) => manifest.header(),
+        }
+    }
+
+    /// The number of copy operations in the state.
+    pub fn len(&self) -> usize {
+        match self.repr {
+            StateRepr::Index(ref data, ref layout) => layout.index(data).len(),
+            StateRepr::Manifest(ref manifest) => manifest.operations.len(),
+        }
+    }
+
+    /// Returns `true` if there are no copy operations in the state.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a sorted list of all destinations.
+    pub fn dests(&self) -> Vec<&Path> {
+        match self.repr {
+            StateRepr::Index(ref data, ref layout) => {
+                let index = layout.index(data);
+                (0..index.len()).map(|i| index.dest(i)).collect()
             }
+            StateRepr::Manifest(ref manifest) => manifest.dests(),
+        }
+    }
 
-            let mut errors: Vec<(&CopyOp, io::Error)> = Vec::new();
-            let mut result: Vec<&CopyOp> = Vec::new();
+    /// Decodes the copy operations whose destinations pass the filter.
+    pub fn operations_where<F>(&self, filter: F) -> Vec<CopyOp>
+    where
+        F: Fn(&Path) -> bool,
+    {
+        match self.repr {
+            StateRepr::Index(ref data, ref layout) => {
+                let index = layout.index(data);
+                (0..index.len())
+                    .filter(|&i| filter(index.dest(i)))
+                    .map(|i| index.op(i))
+                    .collect()
+            }
+            StateRepr::Manifest(ref manifest) => manifest
+                .operations
+                .iter()
+                .filter(|op| filter(&op.dest))
+                .cloned()
+                .collect(),
+        }
+    }
 
-            for (op, complete) in rx.iter().take(self.operations.len()) {
-                match complete {
-                    Ok(false) => result.push(op),
-                    Ok(true) => {}
-                    Err(err) => errors.push((op, err)),
-                };
+    /// Decodes all of the copy operations.
+    pub fn into_manifest(self) -> Manifest {
+        match self.repr {
+            StateRepr::Index(ref data, ref layout) => {
+                let index = layout.index(data);
+                Manifest {
+                    header: layout.header.clone(),
+                    operations: (0..index.len()).map(|i| index.op(i)).collect(),
+                }
             }
+            StateRepr::Manifest(manifest) => manifest,
+        }
+    }
+}
 
-            (errors, result)
-        });
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if errors.is_empty() {
-            log::info!("Found {} outdated copy operations", result.len());
-            Ok(result)
-        } else {
-            Err(errors)
+    use crate::parser::Format;
+    use std::path::PathBuf;
+
+    fn parse(
+        text: &str,
+        options: &ParseOptions,
+    ) -> Result<Manifest, Diagnostics> {
+        Manifest::parse_reader(text.as_bytes(), options)
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let manifest =
+            parse("a\tb\nc\td/e\n", &ParseOptions::new().dest_dir("out"))
+                .unwrap();
+
+        let mut state = Vec::new();
+        manifest.write_state(&mut state).unwrap();
+        assert!(state.starts_with(state::MAGIC));
+
+        // The destinations must not get rebased a second time.
+        let options = ParseOptions::new()
+            .dest_dir("out")
+            .sandbox_src(true)
+            .sandbox_dest(true);
+        let parsed = Manifest::parse_state(&state[..], &options).unwrap();
+        assert_eq!(parsed.operations(), manifest.operations());
+    }
+
+    #[test]
+    fn unsorted_state() {
+        let ops = [
+            CopyOp::new("b".into(), "out/b".into()),
+            CopyOp::new("a".into(), "out/a".into()),
+            CopyOp::new("a".into(), "out/a".into()),
+        ];
+
+        let mut data = Vec::new();
+        state::write(&mut data, None, &ops).unwrap();
+
+        let state = State::parse(&data[..], &ParseOptions::new()).unwrap();
+        assert_eq!(state.len(), 2);
+        assert_eq!(state.dests(), [Path::new("out/a"), Path::new("out/b")]);
+    }
+
+    #[test]
+    fn corrupt_state() {
+        let mut state = Vec::new();
+        parse("a\tb\n", &ParseOptions::new())
+            .unwrap()
+            .write_state(&mut state)
+            .unwrap();
+        state.pop();
+
+        let err = Manifest::parse_state(&state[..], &ParseOptions::new())
+            .unwrap_err();
+        assert_eq!(err[0].message, "Invalid state: Unexpected end of state");
+    }
+
+    #[test]
+    fn state_from_manifest() {
+        let options = ParseOptions::new().dest_dir("out");
+        let parsed = Manifest::parse_state(&b"a\tb\n"[..], &options).unwrap();
+        assert_eq!(
+            parsed.operations(),
+            &[CopyOp::new(PathBuf::from("a"), PathBuf::from("out/b"))]
+        );
+    }
+
+    #[test]
+    fn headers() {
+        let text = "#% version: 1\n#% dest: out\n# A comment\na\tb\n";
+        let manifest = parse(text, &ParseOptions::new()).unwrap();
+        let header = manifest.header().unwrap();
+        assert_eq!(header.dest.as_deref(), Some(Path::new("out")));
+
+        // The header is carried over into the state.
+        let mut state = Vec::new();
+        manifest.write_state(&mut state).unwrap();
+        let parsed =
+            Manifest::parse_state(&state[..], &ParseOptions::new()).unwrap();
+        assert_eq!(parsed.header(), manifest.header());
+        assert_eq!(parsed.operations(), manifest.operations());
+
+        // Only the leading block of fields is the header. Later or
+        // malformed lines with the prefix are comments.
+        let text = "#%%%\n#% version: 1\na\tb\n#% note\n#% version: 2\n";
+        let manifest = parse(text, &ParseOptions::new()).unwrap();
+        assert_eq!(manifest.header(), None);
+        assert_eq!(manifest.operations().len(), 1);
+
+        let text = "#% version: 1\n#% note\n#% colour: red\na\tb\n";
+        let manifest = parse(text, &ParseOptions::new()).unwrap();
+        assert_eq!(manifest.header().unwrap().version, 1);
+
+        let err = Manifest::parse_state(
+            &b"#% version: 2\na\tb\n"[..],
+            &ParseOptions::new(),
+        )
+        .unwrap_err();
+        assert_eq!((err[0].line, err[0].column), (1, 1));
+        assert!(err[0].message.starts_with("Manifest version 2 is newer"));
+    }
+
+    #[test]
+    fn json_lines() {
+        let text = r#"
+# A comment
+{"src": "a", "dest": "b", "hash": "sha256:1234", "tags": ["x", "y"]}
+
+{"src": "c", "dest": "d", "kind": "copy"}
+"#;
+
+        let manifest =
+            parse(text, &ParseOptions::new().dest_dir("out")).unwrap();
+        let ops = manifest.operations();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].src, Path::new("a"));
+        assert_eq!(ops[0].dest, Path::new("out/b"));
+        assert_eq!(ops[0].hash.as_deref(), Some("sha256:1234"));
+        assert_eq!(ops[0].tags, ["x", "y"]);
+        assert_eq!(ops[1].dest, Path::new("out/d"));
+
+        // The format can also be given explicitly.
+        let options = ParseOptions::new().format(Format::Tsv);
+        let manifest = parse("{a}\tb\n", &options).unwrap();
+        assert_eq!(manifest.operations()[0].src, Path::new("{a}"));
+
+        let options = ParseOptions::new().format(Format::JsonLines);
+        assert!(parse("a\tb\n", &options).is_err());
+    }
+
+    #[test]
+    fn merges_duplicate_entries() {
+        let text = r#"
+{"src": "a", "dest": "b", "hash": "sha256:1234", "tags": ["x"]}
+{"src": "a", "dest": "b", "hash": "sha256:1234", "tags": ["x"]}
+{"src": "a", "dest": "b", "kind": "hardlink"}
+{"src": "c", "dest": "a"}
+"#;
+
+        let manifest = parse(text, &ParseOptions::new()).unwrap();
+        let ops = manifest.operations();
+
+        // Entries are ordered by destination.
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0].dest, Path::new("a"));
+        assert_eq!(ops[1].kind, OpKind::Copy);
+        assert_eq!(ops[1].hash.as_deref(), Some("sha256:1234"));
+        assert_eq!(ops[2].kind, OpKind::Hardlink);
+
+        // Entries that would be merged have to agree on everything else.
+        let text = r#"
+{"src": "a", "dest": "b", "tags": ["x"]}
+{"src": "c", "dest": "d"}
+{"src": "a", "dest": "b", "tags": ["y"]}
+{"src": "a", "dest": "b", "tags": ["x"], "mode": "0644"}
+"#;
+
+        let err = parse(text, &ParseOptions::new()).unwrap_err();
+        let lines: Vec<usize> = err.iter().map(|d| d.line).collect();
+        assert_eq!(lines, [4, 5]);
+        assert_eq!(
+            err[0].message,
+            "Duplicate entry with different options than line 2"
+        );
+
+        // A state with duplicate entries is merged the same way.
+        let ops = vec![ops[1].clone(), ops[1].clone()];
+        let mut state = Vec::new();
+        state::write(&mut state, None, &ops).unwrap();
+        let parsed = Manifest::parse_state(&state[..], &ParseOptions::new());
+        assert_eq!(parsed.unwrap().operations().len(), 1);
+    }
+
+    #[test]
+    fn json_lines_errors() {
+        let options = ParseOptions::new();
+
+        for text in &[
+            r#"{"src": "a"}"#,
+            r#"{"src": "a", "dest": "b", "colour": "red"}"#,
+            r#"{"src": "a", "dest": "b", "kind": "bogus"}"#,
+            r#"{"src": "a", "dest": "b"#,
+            r#"{"src": "../a", "dest": "b"}"#,
+        ] {
+            let options = options.clone().sandbox_src(true);
+            let err = parse(text, &options).unwrap_err();
+            assert!(!err.is_empty());
+        }
+
+        let err = parse("\n{}\n", &options).unwrap_err();
+        assert_eq!(err[0].line, 2);
+    }
+
+    #[test]
+    fn null_delimited() {
+        let options = ParseOptions::new().format(Format::Null);
+
+        let text = " a\tb \0dest/\n#c\0src\0dest/d \0";
+        let manifest = parse(text, &options).unwrap();
+        assert_eq!(
+            manifest.operations(),
+            &[
+                CopyOp::new(
+                    PathBuf::from(" a\tb "),
+                    PathBuf::from("dest/\n#c")
+                ),
+                CopyOp::new(PathBuf::from("src"), PathBuf::from("dest/d ")),
+            ]
+        );
+
+        // State keeps these paths intact.
+        let mut state = Vec::new();
+        manifest.write_state(&mut state).unwrap();
+        let parsed =
+            Manifest::parse_state(&state[..], &ParseOptions::new()).unwrap();
+        assert_eq!(parsed.operations(), manifest.operations());
+
+        let err = parse("a\0b\0c\0", &options).unwrap_err();
+        assert_eq!((err[0].line, err[0].column), (2, 2));
+
+        assert!(parse("a\0\0", &options).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // "café" in Latin-1.
+        let name = OsStr::from_bytes(b"caf\xe9");
+
+        for (text, format) in &[
+            (&b"src/caf\xe9\tdest/caf\xe9\n"[..], Format::Auto),
+            (&b"src/caf\xe9\0dest/caf\xe9\0"[..], Format::Null),
+        ] {
+            let options = ParseOptions::new().format(*format);
+            let manifest = Manifest::parse_reader(*text, &options).unwrap();
+
+            let op = &manifest.operations()[0];
+            assert_eq!(op.src, Path::new("src").join(name));
+            assert_eq!(op.dest, Path::new("dest").join(name));
+            assert_eq!(
+                op.to_string(),
+                "\"src/caf\u{fffd}\" -> \"dest/caf\u{fffd}\""
+            );
+
+            let mut state = Vec::new();
+            manifest.write_state(&mut state).unwrap();
+            let parsed =
+                Manifest::parse_state(&state[..], &ParseOptions::new())
+                    .unwrap();
+            assert_eq!(parsed.operations(), manifest.operations());
         }
     }
 }
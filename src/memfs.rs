@@ -0,0 +1,597 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! An in-memory file system. This makes it possible to exercise the full sync
+//! algorithm deterministically without touching the disk.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::faultfs::Contents;
+use crate::filesystem::{FileSystem, FileType, Metadata, RemoveDir};
+use crate::util::PathExt;
+
+#[derive(Clone, Debug)]
+enum Node {
+    File {
+        data: Vec<u8>,
+        modified: SystemTime,
+
+        /// The permission bits. The file is read-only if the owner can't
+        /// write to it.
+        mode: u32,
+
+        /// Identifies the file. Hard links share the same id, but not their
+        /// contents.
+        id: u64,
+    },
+    Dir,
+    Symlink {
+        target: PathBuf,
+    },
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Node::File {
+                data,
+                modified,
+                mode,
+                id,
+            } => Metadata {
+                file_type: FileType::File,
+                len: data.len() as u64,
+                modified: *modified,
+                readonly: mode & 0o200 == 0,
+                mode: *mode,
+                id: Some((0, *id)),
+            },
+            Node::Dir => Metadata {
+                file_type: FileType::Dir,
+                len: 0,
+                modified: SystemTime::UNIX_EPOCH,
+                readonly: false,
+                mode: 0o755,
+                id: None,
+            },
+            Node::Symlink { target } => Metadata {
+                file_type: FileType::Symlink,
+                len: target.as_os_str().len() as u64,
+                modified: SystemTime::UNIX_EPOCH,
+                readonly: false,
+                mode: 0o777,
+                id: None,
+            },
+        }
+    }
+}
+
+/// The mode of newly written files.
+const DEFAULT_MODE: u32 = 0o644;
+
+/// The most symlinks that are followed when looking up a path.
+const MAX_LINKS: usize = 40;
+
+#[derive(Debug)]
+struct State {
+    nodes: BTreeMap<PathBuf, Node>,
+
+    /// Each write advances the clock by one second so that modification
+    /// times are deterministic, yet distinct.
+    clock: SystemTime,
+
+    /// The id of the last file that was created.
+    last_id: u64,
+}
+
+/// A file system that only exists in memory. Relative paths are relative to
+/// an implicit root that always exists.
+#[derive(Debug)]
+pub struct MemoryFs {
+    state: Mutex<State>,
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        MemoryFs::new()
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{:?} does not exist", path),
+    )
+}
+
+fn is_a_dir(path: &Path) -> io::Error {
+    io::Error::other(format!("{:?} is a directory", path))
+}
+
+fn not_a_dir(path: &Path) -> io::Error {
+    io::Error::other(format!("{:?} is not a directory", path))
+}
+
+/// Returns the parent of `path` or `None` if it is at the root.
+fn parent(path: &Path) -> Option<&Path> {
+    path.parent().filter(|p| !p.as_os_str().is_empty())
+}
+
+impl State {
+    fn tick(&mut self) -> SystemTime {
+        self.clock += Duration::from_secs(1);
+        self.clock
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    /// Checks that the parent directory of `path` exists.
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match parent(path) {
+            None => Ok(()),
+            Some(p) => match self.nodes.get(p) {
+                Some(Node::Dir) => Ok(()),
+                Some(_) => Err(not_a_dir(p)),
+                None => Err(not_found(p)),
+            },
+        }
+    }
+
+    /// Checks that a file can be written at `path`.
+    fn check_writable(&self, path: &Path) -> io::Result<()> {
+        self.check_parent(path)?;
+
+        match self.nodes.get(path) {
+            Some(Node::Dir) => Err(is_a_dir(path)),
+            Some(Node::File { mode, .. }) if mode & 0o200 == 0 => {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{:?} is read-only", path),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+        if let Some(p) = parent(path) {
+            self.create_dir_all(p)?;
+        }
+
+        match self.nodes.get(path) {
+            Some(Node::Dir) => Ok(()),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} is a file", path),
+            )),
+            None => {
+                self.nodes.insert(path.to_path_buf(), Node::Dir);
+                Ok(())
+            }
+        }
+    }
+
+    /// Follows symlinks until `path` names something that isn't one. Link
+    /// targets are relative to the directory that the link is in.
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut path = path.to_path_buf();
+
+        for _ in 0..MAX_LINKS {
+            match self.nodes.get(&path) {
+                Some(Node::Symlink { target }) => {
+                    path = match path.parent() {
+                        Some(dir) => dir.join(target).norm(),
+                        None => target.norm(),
+                    };
+                }
+                _ => return Ok(path),
+            }
+        }
+
+        Err(io::Error::other(format!(
+            "{:?} has too many symlinks",
+            path
+        )))
+    }
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        MemoryFs {
+            state: Mutex::new(State {
+                nodes: BTreeMap::new(),
+                clock: SystemTime::UNIX_EPOCH,
+                last_id: 0,
+            }),
+        }
+    }
+
+    /// Writes a file, creating its parent directories as needed. The file's
+    /// modification time is set to the next tick of the clock.
+    pub fn write<P, D>(&self, path: P, data: D)
+    where
+        P: AsRef<Path>,
+        D: Into<Vec<u8>>,
+    {
+        let path = path.as_ref();
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(p) = parent(path) {
+            state.create_dir_all(p).unwrap();
+        }
+
+        let modified = state.tick();
+        let id = state.next_id();
+
+        state.nodes.insert(
+            path.to_path_buf(),
+            Node::File {
+                data: data.into(),
+                modified,
+                mode: DEFAULT_MODE,
+                id,
+            },
+        );
+    }
+
+    /// Returns the contents of a file or `None` if it isn't a file.
+    pub fn read<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let path = state.resolve(path.as_ref()).ok()?;
+
+        match state.nodes.get(&path) {
+            Some(Node::File { data, .. }) => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if a file or directory exists at `path`.
+    pub fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.state.lock().unwrap().nodes.contains_key(path.as_ref())
+    }
+
+    /// Sets the read-only flag on a file.
+    pub fn set_readonly<P: AsRef<Path>>(&self, path: P, readonly: bool) {
+        if let Some(Node::File { mode, .. }) =
+            self.state.lock().unwrap().nodes.get_mut(path.as_ref())
+        {
+            if readonly {
+                *mode &= !0o222;
+            } else {
+                *mode |= 0o200;
+            }
+        }
+    }
+
+    /// Returns a sorted list of every file and directory.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.state.lock().unwrap().nodes.keys().cloned().collect()
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.state.lock().unwrap();
+        let path = state.resolve(path)?;
+
+        match state.nodes.get(&path) {
+            Some(Node::Symlink { .. }) | None => Err(not_found(&path)),
+            Some(node) => Ok(node.metadata()),
+        }
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.state.lock().unwrap();
+
+        match state.nodes.get(path) {
+            Some(node) => Ok(node.metadata()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn copy(
+        &self,
+        from: &Path,
+        to: &Path,
+        progress: Option<&mut dyn FnMut(u64)>,
+    ) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let from = state.resolve(from)?;
+
+        let (mut node, len) = match state.nodes.get(&from) {
+            Some(node @ Node::File { data, .. }) => {
+                (node.clone(), data.len() as u64)
+            }
+            Some(Node::Dir) => return Err(is_a_dir(&from)),
+            Some(Node::Symlink { .. }) | None => return Err(not_found(&from)),
+        };
+
+        state.check_writable(to)?;
+
+        // The copy is a different file.
+        if let Node::File { ref mut id, .. } = node {
+            *id = state.next_id();
+        }
+
+        state.nodes.insert(to.to_path_buf(), node);
+
+        // Everything is written at once.
+        if let Some(progress) = progress {
+            if len > 0 {
+                progress(len);
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.nodes.get(path) {
+            Some(Node::File { .. }) | Some(Node::Symlink { .. }) => {
+                state.nodes.remove(path);
+                Ok(())
+            }
+            Some(Node::Dir) => Err(is_a_dir(path)),
+            None => Ok(()),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<RemoveDir> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.nodes.get(path) {
+            Some(Node::Dir) => {
+                let empty = state
+                    .nodes
+                    .range(path.to_path_buf()..)
+                    .nth(1)
+                    .is_none_or(|(p, _)| !p.starts_with(path));
+
+                if !empty {
+                    return Ok(RemoveDir::NotEmpty);
+                }
+
+                state.nodes.remove(path);
+                Ok(RemoveDir::Removed)
+            }
+            Some(_) => Err(not_a_dir(path)),
+            None => Ok(RemoveDir::Missing),
+        }
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let path = state.resolve(path)?;
+
+        match state.nodes.get_mut(&path) {
+            Some(Node::File { mode: m, .. }) => {
+                *m = mode;
+                Ok(())
+            }
+            Some(Node::Dir) => Err(is_a_dir(&path)),
+            Some(Node::Symlink { .. }) | None => Err(not_found(&path)),
+        }
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.state.lock().unwrap().nodes.get(path) {
+            Some(Node::Symlink { target }) => Ok(target.clone()),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a symlink", path),
+            )),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn hard_link(&self, src: &Path, dest: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let src = state.resolve(src)?;
+
+        let node = match state.nodes.get(&src) {
+            Some(node @ Node::File { .. }) => node.clone(),
+            Some(Node::Dir) => return Err(is_a_dir(&src)),
+            Some(Node::Symlink { .. }) | None => return Err(not_found(&src)),
+        };
+
+        state.check_parent(dest)?;
+
+        if state.nodes.contains_key(dest) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", dest),
+            ));
+        }
+
+        state.nodes.insert(dest.to_path_buf(), node);
+
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.check_parent(path)?;
+
+        if state.nodes.contains_key(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", path),
+            ));
+        }
+
+        state.nodes.insert(
+            path.to_path_buf(),
+            Node::Symlink {
+                target: target.to_path_buf(),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.state.lock().unwrap().create_dir_all(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+
+        // Paths are relative to the root, so `.` is the root itself.
+        let path = if path == Path::new(".") {
+            Path::new("")
+        } else {
+            path
+        };
+
+        match state.nodes.get(path) {
+            // The implicit root always exists.
+            Some(Node::Dir) => {}
+            None if path.as_os_str().is_empty() => {}
+            Some(_) => return Err(not_a_dir(path)),
+            None => return Err(not_found(path)),
+        };
+
+        Ok(state
+            .nodes
+            .keys()
+            .filter(|p| p.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+}
+
+impl Contents for MemoryFs {
+    fn read_contents(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let path = state.resolve(path)?;
+
+        match state.nodes.get(&path) {
+            Some(Node::File { data, .. }) => Ok(data.clone()),
+            Some(Node::Dir) => Err(is_a_dir(&path)),
+            Some(Node::Symlink { .. }) | None => Err(not_found(&path)),
+        }
+    }
+
+    fn write_contents(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.check_writable(path)?;
+
+        let modified = state.tick();
+        let id = state.next_id();
+
+        state.nodes.insert(
+            path.to_path_buf(),
+            Node::File {
+                data: data.to_vec(),
+                modified,
+                mode: DEFAULT_MODE,
+                id,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_preserves_metadata() {
+        let fs = MemoryFs::new();
+        fs.write("src/a", "hello");
+        fs.set_readonly("src/a", true);
+
+        assert_eq!(
+            fs.copy(Path::new("src/a"), Path::new("dest/a"), None)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+
+        fs.create_dir_all(Path::new("dest")).unwrap();
+        let mut progress = Vec::new();
+        assert_eq!(
+            fs.copy(
+                Path::new("src/a"),
+                Path::new("dest/a"),
+                Some(&mut |n| progress.push(n))
+            )
+            .unwrap(),
+            5
+        );
+        assert_eq!(progress, [5]);
+
+        // The copy is a different file with the same metadata.
+        let src = fs.metadata(Path::new("src/a")).unwrap();
+        let dest = fs.metadata(Path::new("dest/a")).unwrap();
+        assert_ne!(src.id, dest.id);
+        assert_eq!(Metadata { id: None, ..src }, Metadata { id: None, ..dest });
+    }
+
+    #[test]
+    fn remove_dir() {
+        let fs = MemoryFs::new();
+        fs.write("a/b/c", "");
+        fs.write("ab", "");
+
+        let remove_dir = |path| fs.remove_dir(Path::new(path)).unwrap();
+
+        assert_eq!(remove_dir("a"), RemoveDir::NotEmpty);
+        assert_eq!(remove_dir("a/b"), RemoveDir::NotEmpty);
+        fs.remove_file(Path::new("a/b/c")).unwrap();
+        assert_eq!(remove_dir("a/b"), RemoveDir::Removed);
+        assert_eq!(remove_dir("a"), RemoveDir::Removed);
+        assert_eq!(remove_dir("a"), RemoveDir::Missing);
+        assert_eq!(fs.paths(), vec![PathBuf::from("ab")]);
+    }
+
+    #[test]
+    fn read_dir() {
+        let fs = MemoryFs::new();
+        fs.write("a/b/c", "");
+        fs.write("a/d", "");
+
+        let mut paths = fs.read_dir(Path::new("a")).unwrap();
+        paths.sort();
+        assert_eq!(paths, vec![PathBuf::from("a/b"), PathBuf::from("a/d")]);
+
+        assert_eq!(
+            fs.read_dir(Path::new("a/d")).unwrap_err().kind(),
+            io::ErrorKind::Other
+        );
+        assert_eq!(
+            fs.read_dir(Path::new("x")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::io;
+use std::path::Path;
+
+use crate::copyop::CopyOp;
+
+/// The phases of a sync, in the order in which they happen.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Phase {
+    /// Checking the next manifest for race conditions.
+    CheckRaces,
+
+    /// Deleting destinations that were removed from the manifest.
+    Delete,
+
+    /// Deleting the parent directories of deleted destinations.
+    DeleteDirs,
+
+    /// Finding out which copy operations are out of date.
+    Outdated,
+
+    /// Creating parent directories for outdated destinations.
+    CreateDirs,
+
+    /// Copying outdated files.
+    Copy,
+
+    /// Checking that nothing is out of date after copying.
+    Verify,
+}
+
+/// The thing that an operation failed on.
+#[derive(Clone, Copy, Debug)]
+pub enum Target<'a> {
+    /// A destination file or directory.
+    Path(&'a Path),
+
+    /// A copy operation.
+    Op(&'a CopyOp),
+}
+
+/// Receives events while a sync is in progress. All methods do nothing by
+/// default so that implementors only need to override the events they care
+/// about.
+///
+/// Most events are sent from the worker threads, so implementations must be
+/// thread-safe and should return quickly.
+pub trait SyncObserver: Send + Sync {
+    /// A phase has started.
+    fn phase_start(&self, _phase: Phase) {}
+
+    /// A phase has finished. This is not called if the phase failed, unless
+    /// the sync is keeping going after failures.
+    fn phase_end(&self, _phase: Phase) {}
+
+    /// A destination file was deleted.
+    fn deleted(&self, _path: &Path) {}
+
+    /// An empty directory was deleted.
+    fn dir_deleted(&self, _path: &Path) {}
+
+    /// A copy operation was checked. `outdated` is `true` if it needs to be
+    /// copied.
+    fn checked(&self, _op: &CopyOp, _outdated: bool) {}
+
+    /// A destination directory was created.
+    fn dir_created(&self, _path: &Path) {}
+
+    /// A copy is about to start.
+    fn copy_start(&self, _op: &CopyOp) {}
+
+    /// Data has been written for a copy. `copied` is the total number of
+    /// bytes written so far. This is called after every chunk that is written,
+    /// so it can be used to show the progress of large files. Links and
+    /// copies that only fix the mode don't write any data.
+    fn copy_progress(&self, _op: &CopyOp, _copied: u64) {}
+
+    /// Whether `copy_progress` should be called. Copies that don't report
+    /// progress can use faster ways of copying, so observers that ignore it
+    /// should return `false`.
+    fn wants_progress(&self) -> bool {
+        true
+    }
+
+    /// A copy finished successfully.
+    fn copy_finished(&self, _op: &CopyOp) {}
+
+    /// Something failed during the given phase. The failures are also
+    /// reported in the `Error` returned by `sync`.
+    fn failed(&self, _phase: Phase, _target: Target<'_>, _error: &io::Error) {}
+}
+
+/// An observer that ignores everything.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoObserver;
+
+impl SyncObserver for NoObserver {
+    fn wants_progress(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,1714 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use glob::Pattern;
+use serde::Deserialize;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::Arc;
+
+use crate::copyop::{CopyOp, OpKind};
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::filesystem::{FileSystem, FileType, RealFs};
+use crate::header::{header_field, Header};
+use crate::util::{self, PathExt};
+use crate::vars;
+
+/// The format of a manifest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// Detect the format from the first entry. If it starts with `{`, the
+    /// manifest is JSON Lines. Otherwise, it is tab-separated.
+    Auto,
+
+    /// One `src<TAB>dest` entry per line. Any further columns are
+    /// `key=value` options, such as `kind=tree`.
+    Tsv,
+
+    /// One JSON object per line with `src` and `dest` fields and optionally
+    /// `kind`, `mode`, `hash` and `tags`.
+    JsonLines,
+
+    /// Sources and destinations each terminated by a NUL byte, like the output
+    /// of `find -print0`. Paths are taken exactly as they are, so they may
+    /// contain any character other than NUL. There are no comments.
+    Null,
+}
+
+/// Options that control how a manifest is parsed.
+#[derive(Clone)]
+pub struct ParseOptions {
+    src_dir: PathBuf,
+    dest_dir: PathBuf,
+    include_dir: Option<PathBuf>,
+    sandbox_src: bool,
+    sandbox_dest: bool,
+    format: Format,
+    allow_empty_globs: bool,
+    variables: Option<BTreeMap<String, String>>,
+    filesystem: Arc<dyn FileSystem>,
+}
+
+impl fmt::Debug for ParseOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParseOptions")
+            .field("src_dir", &self.src_dir)
+            .field("dest_dir", &self.dest_dir)
+            .field("include_dir", &self.include_dir)
+            .field("sandbox_src", &self.sandbox_src)
+            .field("sandbox_dest", &self.sandbox_dest)
+            .field("format", &self.format)
+            .field("allow_empty_globs", &self.allow_empty_globs)
+            .field("variables", &self.variables)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            src_dir: PathBuf::new(),
+            dest_dir: PathBuf::new(),
+            include_dir: None,
+            sandbox_src: false,
+            sandbox_dest: false,
+            format: Format::Auto,
+            allow_empty_globs: false,
+            variables: None,
+            filesystem: Arc::new(RealFs),
+        }
+    }
+}
+
+impl ParseOptions {
+    pub fn new() -> Self {
+        ParseOptions::default()
+    }
+
+    /// Makes all source paths relative to this directory. The targets of
+    /// symlinks are left alone.
+    pub fn src_dir<P: Into<PathBuf>>(mut self, src_dir: P) -> Self {
+        self.src_dir = src_dir.into();
+        self
+    }
+
+    /// Makes all destination paths relative to this directory.
+    pub fn dest_dir<P: Into<PathBuf>>(mut self, dest_dir: P) -> Self {
+        self.dest_dir = dest_dir.into();
+        self
+    }
+
+    /// Makes the includes in the outermost manifest relative to this directory
+    /// instead of the directory of the manifest. This is useful if the
+    /// manifest was copied from somewhere else before it is parsed.
+    pub fn include_dir<P: Into<PathBuf>>(mut self, include_dir: P) -> Self {
+        self.include_dir = Some(include_dir.into());
+        self
+    }
+
+    /// Don't allow source paths to escape the source directory.
+    pub fn sandbox_src(mut self, sandbox_src: bool) -> Self {
+        self.sandbox_src = sandbox_src;
+        self
+    }
+
+    /// Don't allow destination paths to escape the destination directory.
+    pub fn sandbox_dest(mut self, sandbox_dest: bool) -> Self {
+        self.sandbox_dest = sandbox_dest;
+        self
+    }
+
+    /// The format of the manifest. This is detected automatically by default.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Only warn about glob sources that don't match any files instead of
+    /// treating them as errors.
+    pub fn allow_empty_globs(mut self, allow_empty_globs: bool) -> Self {
+        self.allow_empty_globs = allow_empty_globs;
+        self
+    }
+
+    /// Looks up the variables referenced in paths in this map instead of the
+    /// environment.
+    pub fn variables(mut self, variables: BTreeMap<String, String>) -> Self {
+        self.variables = Some(variables);
+        self
+    }
+
+    /// Returns the value of a variable referenced in a path.
+    fn variable(&self, name: &str) -> Option<String> {
+        match self.variables {
+            Some(ref variables) => variables.get(name).cloned(),
+            None => env::var(name).ok(),
+        }
+    }
+
+    /// The file system in which directory tree and glob entries are expanded.
+    /// This is the real file system by default.
+    pub fn filesystem(mut self, filesystem: Arc<dyn FileSystem>) -> Self {
+        self.filesystem = filesystem;
+        self
+    }
+}
+
+/// The kind of a manifest entry.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    #[default]
+    Copy,
+    Tree,
+    Glob,
+    Symlink,
+    Hardlink,
+}
+
+impl Kind {
+    /// The kind of the copy operations that an entry of this kind becomes.
+    fn op_kind(self) -> OpKind {
+        match self {
+            Kind::Symlink => OpKind::Symlink,
+            Kind::Hardlink => OpKind::Hardlink,
+            Kind::Copy | Kind::Tree | Kind::Glob => OpKind::Copy,
+        }
+    }
+
+    fn from_name(name: &[u8]) -> Option<Kind> {
+        match name {
+            b"copy" => Some(Kind::Copy),
+            b"tree" => Some(Kind::Tree),
+            b"glob" => Some(Kind::Glob),
+            b"symlink" => Some(Kind::Symlink),
+            b"hardlink" => Some(Kind::Hardlink),
+            _ => None,
+        }
+    }
+}
+
+/// Include and exclude patterns for the files of a directory tree entry.
+/// They are matched against paths relative to the root of the tree, using
+/// `/` as the separator. A file is copied if it matches any of the include
+/// patterns (or there are none) and none of the exclude patterns.
+#[derive(Default)]
+struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        (self.include.is_empty()
+            || self.include.iter().any(|p| p.matches(path)))
+            && !self.exclude.iter().any(|p| p.matches(path))
+    }
+}
+
+/// Returns `true` if a component of a glob's source path is a pattern rather
+/// than a literal name.
+fn is_glob(path: &[u8]) -> bool {
+    path.iter().any(|b| matches!(b, b'*' | b'?' | b'['))
+}
+
+/// How an entry is turned into copy operations.
+#[derive(Clone, Copy)]
+enum Expand<'f> {
+    /// The entry is a single file.
+    File,
+
+    /// The entry is a directory tree. Every file in it that passes the filter
+    /// is copied.
+    Tree(&'f Filter),
+
+    /// The source is a glob pattern and the destination is a directory. Every
+    /// matching file is copied into the directory.
+    Glob,
+}
+
+impl<'f> Expand<'f> {
+    fn new(kind: Kind, filter: &'f Filter) -> Self {
+        match kind {
+            Kind::Tree => Expand::Tree(filter),
+            Kind::Glob => Expand::Glob,
+            Kind::Copy | Kind::Symlink | Kind::Hardlink => Expand::File,
+        }
+    }
+}
+
+/// Joins the components of a relative path with `/`.
+fn slash_path(path: &Path) -> String {
+    let components: Vec<_> = path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+
+    components.join("/")
+}
+
+/// The type of a file found while expanding a tree or a glob. Symlinks are
+/// followed, except that a link to a directory is reported as a symlink. Such
+/// links are not walked, as they could lead back to one of their parents.
+fn entry_type(fs: &dyn FileSystem, path: &Path) -> io::Result<FileType> {
+    match fs.symlink_metadata(path)?.file_type {
+        FileType::Symlink => match fs.metadata(path)?.file_type {
+            FileType::Dir => Ok(FileType::Symlink),
+            file_type => Ok(file_type),
+        },
+        file_type => Ok(file_type),
+    }
+}
+
+/// A single entry in a JSON Lines manifest.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonEntry {
+    src: String,
+    dest: String,
+    #[serde(default)]
+    kind: Kind,
+    mode: Option<String>,
+    hash: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+/// Returns the path of an `include <path>` line. A line with a tab in it is
+/// always an entry.
+fn include_directive(line: &[u8]) -> Option<&[u8]> {
+    let rest = line.strip_prefix(b"include")?;
+
+    if rest.contains(&b'\t') || !rest.first()?.is_ascii_whitespace() {
+        return None;
+    }
+
+    let path = rest.trim_ascii_start();
+
+    if path.is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// A path as it appears in a manifest, along with where it appears.
+struct Field<'b> {
+    text: &'b [u8],
+    column: usize,
+}
+
+/// Collects the copy operations and the problems found while parsing a
+/// manifest. Parsing carries on after a problem so that all of them can be
+/// reported at once.
+pub(crate) struct Parser<'a> {
+    options: &'a ParseOptions,
+
+    /// The manifest currently being parsed.
+    file: Option<PathBuf>,
+
+    /// The directory that includes in the current manifest are relative to.
+    include_dir: Option<PathBuf>,
+
+    /// The canonical paths of the manifests currently being parsed, starting
+    /// with the outermost one. Used to detect include cycles.
+    including: Vec<PathBuf>,
+
+    /// The header of the outermost manifest.
+    header: Option<Header>,
+
+    operations: Vec<CopyOp>,
+    diagnostics: Vec<Diagnostic>,
+
+    /// The manifest and line number that each operation came from.
+    origins: Vec<(Option<PathBuf>, usize)>,
+
+    /// Hard links whose source might be another destination, along with
+    /// what their source would be if it were rebased onto the destination
+    /// directory instead.
+    links: Vec<(usize, PathBuf)>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(options: &'a ParseOptions, file: Option<&Path>) -> Self {
+        Parser {
+            options,
+            file: file.map(Path::to_path_buf),
+            include_dir: options
+                .include_dir
+                .clone()
+                .or_else(|| file.and_then(Path::parent).map(Path::to_path_buf)),
+            including: file
+                .and_then(|file| fs::canonicalize(file).ok())
+                .into_iter()
+                .collect(),
+            header: None,
+            operations: Vec::new(),
+            diagnostics: Vec::new(),
+            origins: Vec::new(),
+            links: Vec::new(),
+        }
+    }
+
+    /// Parses a manifest in the format given by the options.
+    pub fn parse<R: BufRead>(&mut self, reader: R) {
+        if self.options.format == Format::Null {
+            self.parse_null(reader)
+        } else {
+            self.header = self.parse_lines(reader);
+        }
+    }
+
+    /// Returns the header and the copy operations or, if there were any
+    /// problems, all of them.
+    pub fn finish(
+        mut self,
+    ) -> Result<(Option<Header>, Vec<CopyOp>), Diagnostics> {
+        if !self.diagnostics.is_empty() {
+            return Err(Diagnostics::new(self.diagnostics));
+        }
+
+        // The source of a hard link to another destination is rebased like
+        // a destination. This can only be known once all of them are in.
+        let dests: BTreeSet<&Path> =
+            self.operations.iter().map(|op| op.dest.as_path()).collect();
+
+        let links: Vec<(usize, PathBuf)> = self
+            .links
+            .drain(..)
+            .filter(|(_, src)| dests.contains(src.as_path()))
+            .collect();
+
+        for (i, src) in links {
+            self.operations[i].src = src;
+        }
+
+        // Entries for the same copy are merged, which is only safe if they
+        // agree on everything else. The sort is stable, so the later entry
+        // of a pair is the one reported.
+        let mut order: Vec<usize> = (0..self.operations.len()).collect();
+        order.sort_by(|&a, &b| self.operations[a].cmp(&self.operations[b]));
+
+        for pair in order.windows(2) {
+            let (a, b) = (&self.operations[pair[0]], &self.operations[pair[1]]);
+
+            if a == b
+                && (a.mode != b.mode || a.hash != b.hash || a.tags != b.tags)
+            {
+                let (ref file, line) = self.origins[pair[1]];
+                let (ref first_file, first_line) = self.origins[pair[0]];
+
+                let first = if first_file == file {
+                    format!("line {}", first_line)
+                } else {
+                    let name = first_file.as_deref().unwrap_or(Path::new("-"));
+                    format!("'{}' line {}", name.display(), first_line)
+                };
+
+                self.diagnostics.push(Diagnostic {
+                    file: file.clone(),
+                    line,
+                    column: 1,
+                    text: b.dest.display().to_string(),
+                    message: format!(
+                        "Duplicate entry with different options than {}",
+                        first
+                    ),
+                });
+            }
+        }
+
+        if !self.diagnostics.is_empty() {
+            return Err(Diagnostics::new(self.diagnostics));
+        }
+
+        Ok((self.header, self.operations))
+    }
+
+    /// Adds a copy operation that came from the given line.
+    fn add(&mut self, number: usize, op: CopyOp) {
+        self.operations.push(op);
+        self.origins.push((self.file.clone(), number));
+    }
+
+    fn diagnostic<S: Into<String>>(
+        &self,
+        line: usize,
+        column: usize,
+        text: &[u8],
+        message: S,
+    ) -> Diagnostic {
+        Diagnostic {
+            file: self.file.clone(),
+            line,
+            column,
+            text: String::from_utf8_lossy(text).into_owned(),
+            message: message.into(),
+        }
+    }
+
+    fn error<S: Into<String>>(
+        &mut self,
+        line: usize,
+        column: usize,
+        text: &[u8],
+        message: S,
+    ) {
+        let diagnostic = self.diagnostic(line, column, text, message);
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn io_error(&mut self, line: usize, err: io::Error) {
+        self.error(line, 1, b"", format!("Failed to read manifest: {}", err));
+    }
+
+    /// Parses a line-based manifest and returns its header, if it has one.
+    /// The format is detected from the first entry if necessary.
+    fn parse_lines<R: BufRead>(&mut self, reader: R) -> Option<Header> {
+        let mut format = self.options.format;
+        let mut header = None;
+        let mut in_header = true;
+
+        for (i, line) in reader.split(b'\n').enumerate() {
+            let number = i + 1;
+
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    self.io_error(number, err);
+                    break;
+                }
+            };
+
+            let trimmed = line.trim_ascii();
+
+            // Columns are counted from the start of the untrimmed line.
+            let start = line.len() - line.trim_ascii_start().len();
+
+            // Only the leading block of fields is the header. Anything after
+            // it that starts with the prefix is a comment.
+            if in_header {
+                if let Some(field) = header_field(trimmed) {
+                    if let Err(message) =
+                        Header::parse_field(&mut header, field)
+                    {
+                        self.error(number, start + 1, trimmed, message);
+                    }
+
+                    continue;
+                }
+
+                in_header = false;
+            }
+
+            if trimmed.is_empty() || trimmed.starts_with(b"#") {
+                // Ignore blank lines and comments
+                continue;
+            }
+
+            if let Some(path) = include_directive(trimmed) {
+                let column = start + trimmed.len() - path.len() + 1;
+                self.include(number, column, path);
+                continue;
+            }
+
+            if format == Format::Auto {
+                format = if trimmed.starts_with(b"{") {
+                    Format::JsonLines
+                } else {
+                    Format::Tsv
+                };
+            }
+
+            match format {
+                Format::JsonLines => self.parse_json(trimmed, number, start),
+                _ => self.parse_tsv(trimmed, number, start),
+            };
+        }
+
+        header
+    }
+
+    /// Parses the manifest included from the given line. Its path is relative
+    /// to the directory of the current manifest, unless the options say
+    /// otherwise for the outermost one.
+    fn include(&mut self, number: usize, column: usize, text: &[u8]) {
+        let expanded = match self.expand_vars(number, column, text) {
+            Some(expanded) => expanded,
+            None => return,
+        };
+
+        let path = match util::path_from_bytes(expanded) {
+            Ok(path) => path,
+            Err(_) => {
+                return self.error(number, column, text, "Invalid include path")
+            }
+        };
+
+        let path = match self.include_dir {
+            Some(ref dir) => dir.join(path),
+            None => path,
+        };
+
+        let f = match File::open(&path) {
+            Ok(f) => f,
+            Err(err) => {
+                return self.error(
+                    number,
+                    column,
+                    text,
+                    format!("Failed to include manifest: {}", err),
+                );
+            }
+        };
+
+        let canonical =
+            fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+
+        if let Some(i) = self.including.iter().position(|p| p == &canonical) {
+            let cycle: Vec<String> = self.including[i..]
+                .iter()
+                .chain(Some(&canonical))
+                .map(|p| p.display().to_string())
+                .collect();
+
+            return self.error(
+                number,
+                column,
+                text,
+                format!("Include cycle: {}", cycle.join(" -> ")),
+            );
+        }
+
+        self.including.push(canonical);
+        let dir = path.parent().map(Path::to_path_buf);
+        let include_dir = mem::replace(&mut self.include_dir, dir);
+        let file = self.file.replace(path);
+
+        // An included manifest's header is checked, but it doesn't describe
+        // the manifest as a whole.
+        self.parse_lines(io::BufReader::new(f));
+
+        self.file = file;
+        self.include_dir = include_dir;
+        self.including.pop();
+    }
+
+    /// Parses a manifest of NUL-terminated source and destination pairs.
+    fn parse_null<R: BufRead>(&mut self, reader: R) {
+        let mut fields = reader.split(b'\0');
+        let mut number = 0;
+
+        while let Some(src) = fields.next() {
+            number += 1;
+
+            let src = match src {
+                Ok(src) => src,
+                Err(err) => return self.io_error(number, err),
+            };
+
+            let dest = match fields.next() {
+                Some(Ok(dest)) => dest,
+                Some(Err(err)) => return self.io_error(number, err),
+                None => {
+                    return self.error(
+                        number,
+                        2,
+                        &src,
+                        "Missing destination file",
+                    );
+                }
+            };
+
+            let src = Field {
+                text: &src,
+                column: 1,
+            };
+            let dest = Field {
+                text: &dest,
+                column: 2,
+            };
+
+            if let Some(op) = self.copy_op(number, &src, &dest) {
+                self.push(op, Expand::File, number, &src, &dest);
+            }
+        }
+    }
+
+    fn parse_tsv(&mut self, line: &[u8], number: usize, start: usize) {
+        let mut columns = line.split(|&b| b == b'\t');
+
+        // There is always at least one column.
+        let src = columns.next().unwrap_or_default();
+
+        let dest = match columns.next() {
+            Some(dest) => dest,
+            None => {
+                return self.error(
+                    number,
+                    start + line.len() + 1,
+                    line,
+                    "Missing destination file",
+                );
+            }
+        };
+
+        let src = Field {
+            text: src,
+            column: start + 1,
+        };
+        let dest = Field {
+            text: dest,
+            column: start + src.text.len() + 2,
+        };
+
+        let mut kind = Kind::Copy;
+        let mut filter = Filter::default();
+        let mut mode = None;
+        let mut column = dest.column + dest.text.len() + 1;
+        let mut ok = true;
+
+        for option in columns {
+            ok &= self.tsv_option(
+                number,
+                column,
+                option,
+                &mut kind,
+                &mut filter,
+                &mut mode,
+            );
+            column += option.len() + 1;
+        }
+
+        ok &= self.check_kind(number, start + 1, line, kind, &filter, mode);
+
+        if let (true, Some(mut op)) = (ok, self.copy_op(number, &src, &dest)) {
+            op.kind = kind.op_kind();
+            op.mode = mode;
+            let expand = Expand::new(kind, &filter);
+            self.push(op, expand, number, &src, &dest);
+        }
+    }
+
+    /// Parses one of the `key=value` options that follow the destination of a
+    /// TSV entry. Empty columns are ignored.
+    fn tsv_option(
+        &mut self,
+        number: usize,
+        column: usize,
+        text: &[u8],
+        kind: &mut Kind,
+        filter: &mut Filter,
+        mode: &mut Option<u32>,
+    ) -> bool {
+        if text.is_empty() {
+            return true;
+        }
+
+        let (key, value) = match text.iter().position(|&b| b == b'=') {
+            Some(i) => (&text[..i], &text[i + 1..]),
+            None => {
+                self.error(number, column, text, "Expected key=value option");
+                return false;
+            }
+        };
+
+        match key {
+            b"kind" => match Kind::from_name(value) {
+                Some(k) => {
+                    *kind = k;
+                    true
+                }
+                None => {
+                    self.error(number, column, text, "Unknown entry kind");
+                    false
+                }
+            },
+            b"include" => self
+                .pattern(number, column, text, value)
+                .map(|p| filter.include.push(p))
+                .is_some(),
+            b"exclude" => self
+                .pattern(number, column, text, value)
+                .map(|p| filter.exclude.push(p))
+                .is_some(),
+            b"mode" => {
+                *mode = self.mode(number, column, text, value);
+                mode.is_some()
+            }
+            _ => {
+                self.error(number, column, text, "Unknown option");
+                false
+            }
+        }
+    }
+
+    fn parse_json(&mut self, line: &[u8], number: usize, start: usize) {
+        let entry: JsonEntry = match serde_json::from_slice(line) {
+            Ok(entry) => entry,
+            Err(err) => {
+                return self.error(
+                    number,
+                    start + err.column().max(1),
+                    line,
+                    format!("Invalid entry: {}", err),
+                );
+            }
+        };
+
+        let mut filter = Filter::default();
+        let mut ok = true;
+
+        for (patterns, texts) in [
+            (&mut filter.include, &entry.include),
+            (&mut filter.exclude, &entry.exclude),
+        ] {
+            for text in texts {
+                match self.pattern(number, start + 1, line, text.as_bytes()) {
+                    Some(pattern) => patterns.push(pattern),
+                    None => ok = false,
+                }
+            }
+        }
+
+        let expand = Expand::new(entry.kind, &filter);
+
+        if !matches!(expand, Expand::File) && entry.hash.is_some() {
+            self.error(
+                number,
+                start + 1,
+                line,
+                "Only single file entries can have a hash",
+            );
+            ok = false;
+        }
+
+        let mode = match entry.mode {
+            Some(ref mode) => {
+                let mode = self.mode(number, start + 1, line, mode.as_bytes());
+                ok &= mode.is_some();
+                mode
+            }
+            None => None,
+        };
+
+        ok &=
+            self.check_kind(number, start + 1, line, entry.kind, &filter, mode);
+
+        let src = Field {
+            text: entry.src.as_bytes(),
+            column: start + 1,
+        };
+        let dest = Field {
+            text: entry.dest.as_bytes(),
+            column: start + 1,
+        };
+
+        if let (true, Some(mut op)) = (ok, self.copy_op(number, &src, &dest)) {
+            op.hash = entry.hash;
+            op.tags = entry.tags;
+            op.kind = entry.kind.op_kind();
+            op.mode = mode;
+            self.push(op, expand, number, &src, &dest);
+        }
+    }
+
+    /// Checks that the kind of an entry goes with its other options.
+    fn check_kind(
+        &mut self,
+        number: usize,
+        column: usize,
+        text: &[u8],
+        kind: Kind,
+        filter: &Filter,
+        mode: Option<u32>,
+    ) -> bool {
+        if kind != Kind::Tree && !filter.is_empty() {
+            self.error(
+                number,
+                column,
+                text,
+                "Only tree entries can have include or exclude patterns",
+            );
+            return false;
+        }
+
+        if let (Kind::Symlink | Kind::Hardlink, Some(_)) = (kind, mode) {
+            self.error(number, column, text, "A link can't have a mode");
+            return false;
+        }
+
+        true
+    }
+
+    /// Parses an octal permission mode such as `0755`.
+    fn mode(
+        &mut self,
+        number: usize,
+        column: usize,
+        text: &[u8],
+        mode: &[u8],
+    ) -> Option<u32> {
+        let parsed = str::from_utf8(mode)
+            .ok()
+            .filter(|mode| !mode.is_empty() && !mode.starts_with('+'))
+            .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+            .filter(|&mode| mode <= 0o7777);
+
+        if parsed.is_none() {
+            self.error(
+                number,
+                column,
+                text,
+                format!("Invalid mode {:?}", String::from_utf8_lossy(mode)),
+            );
+        }
+
+        parsed
+    }
+
+    fn pattern(
+        &mut self,
+        number: usize,
+        column: usize,
+        text: &[u8],
+        pattern: &[u8],
+    ) -> Option<Pattern> {
+        let result = str::from_utf8(pattern)
+            .map_err(|err| err.to_string())
+            .and_then(|p| Pattern::new(p).map_err(|err| err.to_string()));
+
+        match result {
+            Ok(pattern) => Some(pattern),
+            Err(err) => {
+                self.error(
+                    number,
+                    column,
+                    text,
+                    format!("Invalid pattern: {}", err),
+                );
+                None
+            }
+        }
+    }
+
+    /// Converts the paths of an entry into a copy operation.
+    fn copy_op(
+        &mut self,
+        number: usize,
+        src: &Field,
+        dest: &Field,
+    ) -> Option<CopyOp> {
+        let src = self.path(number, src, "source");
+        let dest = self.path(number, dest, "destination");
+
+        Some(CopyOp::new(src?, dest?))
+    }
+
+    fn path(
+        &mut self,
+        number: usize,
+        field: &Field,
+        what: &str,
+    ) -> Option<PathBuf> {
+        let text = self.expand_vars(number, field.column, field.text)?;
+
+        if text.is_empty() {
+            self.error(
+                number,
+                field.column,
+                field.text,
+                format!("Empty {} path", what),
+            );
+            return None;
+        }
+
+        match util::path_from_bytes(text) {
+            Ok(path) => Some(path),
+            Err(_) => {
+                self.error(
+                    number,
+                    field.column,
+                    field.text,
+                    format!("Invalid {} path", what),
+                );
+                None
+            }
+        }
+    }
+
+    /// Expands the variables referenced in a path. Paths in NUL-delimited
+    /// manifests are taken exactly as they are.
+    fn expand_vars(
+        &mut self,
+        number: usize,
+        column: usize,
+        text: &[u8],
+    ) -> Option<Vec<u8>> {
+        let options = self.options;
+
+        if options.format == Format::Null || !vars::has_vars(text) {
+            return Some(text.to_vec());
+        }
+
+        match vars::expand(text, |name| options.variable(name)) {
+            Ok(expanded) => Some(expanded),
+            Err(errors) => {
+                for error in errors {
+                    self.error(number, column, text, error);
+                }
+                None
+            }
+        }
+    }
+
+    /// Normalizes the paths of a copy operation, checks that they are
+    /// sandboxed and rebases the destination onto the destination directory.
+    /// The target of a symlink is kept exactly as it is.
+    /// If the operation is a directory tree or a glob, it is expanded into one
+    /// operation per file.
+    fn push(
+        &mut self,
+        mut op: CopyOp,
+        expand: Expand,
+        number: usize,
+        src: &Field,
+        dest: &Field,
+    ) {
+        let options = self.options;
+        let symlink = op.kind == OpKind::Symlink;
+
+        if !symlink {
+            op.src = op.src.norm();
+        }
+
+        op.dest = op.dest.norm();
+
+        let mut ok = true;
+
+        if options.sandbox_src && !symlink && !op.src.is_sandboxed() {
+            self.error(
+                number,
+                src.column,
+                src.text,
+                "Source path is not sandboxed",
+            );
+            ok = false;
+        }
+
+        if options.sandbox_dest && !op.dest.is_sandboxed() {
+            self.error(
+                number,
+                dest.column,
+                dest.text,
+                "Destination path is not sandboxed",
+            );
+            ok = false;
+        }
+
+        if !ok {
+            return;
+        }
+
+        // Globs are rebased as they are expanded so that the source directory
+        // is never taken as a pattern.
+        let rebase_src = !symlink && !matches!(expand, Expand::Glob);
+
+        if op.kind == OpKind::Hardlink
+            && matches!(expand, Expand::File)
+            && options.src_dir != options.dest_dir
+        {
+            let src = options.dest_dir.join(&op.src).norm();
+            self.links.push((self.operations.len(), src));
+        }
+
+        if rebase_src && !PathExt::is_empty(options.src_dir.as_path()) {
+            op.src = options.src_dir.join(&op.src).norm();
+        }
+
+        if !PathExt::is_empty(options.dest_dir.as_path()) {
+            op.dest = options.dest_dir.join(&op.dest).norm();
+        }
+
+        match expand {
+            Expand::File => self.add(number, op),
+            Expand::Tree(filter) => self.expand_tree(&op, filter, number, src),
+            Expand::Glob => self.expand_glob(&op, number, src),
+        }
+    }
+
+    /// Adds a copy operation for every file under the source directory of
+    /// `tree` that passes the filter. Problems with the file system are
+    /// reported against the source path of the entry.
+    fn expand_tree(
+        &mut self,
+        tree: &CopyOp,
+        filter: &Filter,
+        number: usize,
+        src: &Field,
+    ) {
+        let fs = self.options.filesystem.as_ref();
+        let mut dirs = vec![tree.src.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            let paths = match fs.read_dir(&dir) {
+                Ok(paths) => paths,
+                Err(err) => {
+                    self.error(
+                        number,
+                        src.column,
+                        src.text,
+                        format!(
+                            "Failed to read directory '{}': {}",
+                            dir.display(),
+                            err
+                        ),
+                    );
+                    continue;
+                }
+            };
+
+            for path in paths {
+                match entry_type(fs, &path) {
+                    Ok(FileType::Dir) => dirs.push(path),
+                    Ok(FileType::Symlink) => {
+                        log::debug!("Skipping symlinked directory {:?}", path);
+                    }
+                    Ok(FileType::File) => {
+                        let relative = match path.strip_prefix(&tree.src) {
+                            Ok(relative) => relative,
+                            Err(_) => continue,
+                        };
+
+                        if filter.matches(&slash_path(relative)) {
+                            self.add(
+                                number,
+                                CopyOp {
+                                    src: path.norm(),
+                                    dest: tree.dest.join(relative),
+                                    kind: OpKind::Copy,
+                                    hash: None,
+                                    tags: tree.tags.clone(),
+                                    mode: tree.mode,
+                                },
+                            );
+                        }
+                    }
+                    Err(err) => self.error(
+                        number,
+                        src.column,
+                        src.text,
+                        format!("Failed to read '{}': {}", path.display(), err),
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Adds a copy operation for every file that matches the glob pattern in
+    /// the source of `glob`. Each component of the pattern matches a single
+    /// path component, so `*` never matches `/`. The matching files are
+    /// copied into the destination directory.
+    fn expand_glob(&mut self, glob: &CopyOp, number: usize, src: &Field) {
+        let fs = self.options.filesystem.as_ref();
+        let mut paths = vec![self.options.src_dir.clone()];
+
+        for component in glob.src.components() {
+            let text = component.as_os_str();
+
+            let pattern = match text.to_str() {
+                Some(text) if is_glob(text.as_bytes()) => text,
+                _ => {
+                    for path in &mut paths {
+                        path.push(text);
+                    }
+                    continue;
+                }
+            };
+
+            let pattern = match self.pattern(
+                number,
+                src.column,
+                src.text,
+                pattern.as_bytes(),
+            ) {
+                Some(pattern) => pattern,
+                None => return,
+            };
+
+            let mut matches = Vec::new();
+
+            for dir in paths {
+                let dir = if PathExt::is_empty(dir.as_path()) {
+                    PathBuf::from(".")
+                } else {
+                    dir
+                };
+
+                // A directory that doesn't exist simply has no matches.
+                let entries = match fs.read_dir(&dir) {
+                    Ok(entries) => entries,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        continue
+                    }
+                    Err(err) => {
+                        self.error(
+                            number,
+                            src.column,
+                            src.text,
+                            format!(
+                                "Failed to read directory '{}': {}",
+                                dir.display(),
+                                err
+                            ),
+                        );
+                        continue;
+                    }
+                };
+
+                matches.extend(entries.into_iter().filter(|path| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| pattern.matches(name))
+                }));
+            }
+
+            paths = matches;
+        }
+
+        let mut found = false;
+
+        for path in paths {
+            match entry_type(fs, &path) {
+                Ok(FileType::Dir) | Ok(FileType::Symlink) => {}
+                Ok(FileType::File) => {
+                    // Every match has a file name as it matched a pattern.
+                    let name = path.file_name().unwrap_or_default();
+
+                    self.add(
+                        number,
+                        CopyOp {
+                            dest: glob.dest.join(name),
+                            src: path.norm(),
+                            kind: OpKind::Copy,
+                            hash: None,
+                            tags: glob.tags.clone(),
+                            mode: glob.mode,
+                        },
+                    );
+                    found = true;
+                }
+                Err(err) => self.error(
+                    number,
+                    src.column,
+                    src.text,
+                    format!("Failed to read '{}': {}", path.display(), err),
+                ),
+            }
+        }
+
+        if !found {
+            let message = "Pattern doesn't match any files";
+
+            if self.options.allow_empty_globs {
+                let diagnostic =
+                    self.diagnostic(number, src.column, src.text, message);
+                log::warn!("{}", diagnostic);
+            } else {
+                self.error(number, src.column, src.text, message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::manifest::Manifest;
+    use crate::memfs::MemoryFs;
+
+    fn parse(text: &str, options: &ParseOptions) -> Vec<Diagnostic> {
+        let mut parser = Parser::new(options, Some(Path::new("manifest")));
+        parser.parse(text.as_bytes());
+        parser.finish().unwrap_err().into_vec()
+    }
+
+    fn location(d: &Diagnostic) -> (usize, usize, &str) {
+        (d.line, d.column, d.text.as_str())
+    }
+
+    #[test]
+    fn all_problems_are_reported() {
+        let options = ParseOptions::new().sandbox_src(true).sandbox_dest(true);
+
+        let text = "\
+ok\tok
+  missing
+../a\t../b
+
+a\t/b
+";
+
+        let diagnostics = parse(text, &options);
+        let locations: Vec<_> = diagnostics.iter().map(location).collect();
+        assert_eq!(
+            locations,
+            [
+                (2, 10, "missing"),
+                (3, 1, "../a"),
+                (3, 6, "../b"),
+                (5, 3, "/b"),
+            ]
+        );
+
+        assert!(diagnostics
+            .iter()
+            .all(|d| d.file.as_deref() == Some(Path::new("manifest"))));
+        assert_eq!(diagnostics[0].message, "Missing destination file");
+        assert_eq!(diagnostics[1].message, "Source path is not sandboxed");
+    }
+
+    #[test]
+    fn json_problems() {
+        let options = ParseOptions::new().sandbox_src(true);
+
+        let text = r#"{"src": "a", "dest": "b", "mode": "0855"}
+{"src": "../a", "dest": "b"}
+{"src": "a", "dest": 1}
+"#;
+
+        let diagnostics = parse(text, &options);
+        let lines: Vec<_> = diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, [1, 2, 3]);
+        assert_eq!(diagnostics[1].text, "../a");
+        assert!(diagnostics[2].message.starts_with("Invalid entry"));
+    }
+
+    #[test]
+    fn modes() {
+        let options = ParseOptions::new();
+
+        let text = "a\tb\tmode=0755\nc\td\n";
+        let manifest =
+            Manifest::parse_reader(text.as_bytes(), &options).unwrap();
+        let modes: Vec<_> =
+            manifest.operations().iter().map(|op| op.mode).collect();
+        assert_eq!(modes, [Some(0o755), None]);
+
+        let text = "a\tb\tmode=9\na\tc\tmode=17777\na\td\tmode=\n";
+        let diagnostics = parse(text, &options);
+        let locations: Vec<_> = diagnostics.iter().map(location).collect();
+        assert_eq!(
+            locations,
+            [(1, 5, "mode=9"), (2, 5, "mode=17777"), (3, 5, "mode=")]
+        );
+        assert_eq!(diagnostics[0].message, "Invalid mode \"9\"");
+    }
+
+    #[test]
+    fn symlinks() {
+        let options = ParseOptions::new().dest_dir("out").sandbox_src(true);
+
+        let text = "../lib/a.so.1\tlib/a.so\tkind=symlink\n";
+        let manifest =
+            Manifest::parse_reader(text.as_bytes(), &options).unwrap();
+        let op = &manifest.operations()[0];
+        assert_eq!(op.kind, OpKind::Symlink);
+        assert_eq!(op.src, Path::new("../lib/a.so.1"));
+        assert_eq!(op.dest, Path::new("out/lib/a.so"));
+
+        let text = "a.so.1\ta.so\tkind=symlink\tmode=0755\n";
+        let diagnostics = parse(text, &options);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "A link can't have a mode");
+    }
+
+    #[test]
+    fn src_dir() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("build[1]/a.dll", "");
+        fs.write("build[1]/b.dll", "");
+
+        let options = ParseOptions::new()
+            .src_dir("build[1]")
+            .dest_dir("out")
+            .sandbox_src(true)
+            .filesystem(fs);
+
+        // Sandboxing is relative to the source directory.
+        let diagnostics = parse("../b.txt\tb.txt\n", &options);
+        assert_eq!(diagnostics[0].message, "Source path is not sandboxed");
+
+        let text = "\
+a.txt\ta.txt
+*.dll\tbin\tkind=glob
+a.txt\tlink\tkind=symlink
+";
+        let manifest =
+            Manifest::parse_reader(text.as_bytes(), &options).unwrap();
+        let ops: Vec<_> = manifest
+            .operations()
+            .iter()
+            .map(|op| (op.src.as_path(), op.dest.as_path()))
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                (Path::new("build[1]/a.txt"), Path::new("out/a.txt")),
+                (Path::new("build[1]/a.dll"), Path::new("out/bin/a.dll")),
+                (Path::new("build[1]/b.dll"), Path::new("out/bin/b.dll")),
+                (Path::new("a.txt"), Path::new("out/link")),
+            ]
+        );
+    }
+
+    #[test]
+    fn hardlink_srcs() {
+        let options = ParseOptions::new().src_dir("build").dest_dir("out");
+
+        // A link to another destination is rebased like a destination, no
+        // matter where that destination is in the manifest.
+        let text = "a/big.bin\tb/big.bin\tkind=hardlink\n\
+                    sdk/big.bin\ta/big.bin\tkind=hardlink\n";
+        let manifest =
+            Manifest::parse_reader(text.as_bytes(), &options).unwrap();
+        let ops: Vec<_> = manifest
+            .operations()
+            .iter()
+            .map(|op| (op.src.as_path(), op.dest.as_path()))
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                (Path::new("build/sdk/big.bin"), Path::new("out/a/big.bin")),
+                (Path::new("out/a/big.bin"), Path::new("out/b/big.bin")),
+            ]
+        );
+    }
+
+    #[test]
+    fn variables() {
+        let variables = [("ROOT", "/build"), ("EMPTY", "")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let options = ParseOptions::new().variables(variables);
+
+        let text = "${ROOT}/a\t${OUT:-out}/a\n";
+        let manifest =
+            Manifest::parse_reader(text.as_bytes(), &options).unwrap();
+        assert_eq!(manifest.srcs(), [Path::new("/build/a")]);
+        assert_eq!(manifest.dests(), [Path::new("out/a")]);
+
+        let text = "a\t${MISSING}/b\n${EMPTY}\tc\n";
+        let diagnostics = parse(text, &options);
+        let locations: Vec<_> = diagnostics.iter().map(location).collect();
+        assert_eq!(locations, [(1, 3, "${MISSING}/b"), (2, 1, "${EMPTY}")]);
+        assert_eq!(diagnostics[0].message, "Undefined variable MISSING");
+        assert_eq!(diagnostics[1].message, "Empty source path");
+
+        // Paths in NUL-delimited manifests are never expanded.
+        let options = options.format(Format::Null);
+        let manifest =
+            Manifest::parse_reader(&b"${ROOT}\0b\0"[..], &options).unwrap();
+        assert_eq!(manifest.srcs(), [Path::new("${ROOT}")]);
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ubercopy-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn includes() {
+        let dir = temp_dir("includes");
+
+        fs::write(dir.join("manifest"), "a\tb\ninclude sub/part\n").unwrap();
+        fs::write(
+            dir.join("sub/part"),
+            "{\"src\": \"c\", \"dest\": \"d\"}\ninclude  missing\n",
+        )
+        .unwrap();
+
+        let options = ParseOptions::new();
+        let diagnostics =
+            Manifest::parse(dir.join("manifest"), &options).unwrap_err();
+
+        // The problem is reported where it is in the included manifest.
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file, Some(dir.join("sub/part")));
+        assert_eq!(location(&diagnostics[0]), (2, 10, "missing"));
+
+        fs::write(dir.join("sub/missing"), "e\tf\n").unwrap();
+
+        let manifest = Manifest::parse(dir.join("manifest"), &options).unwrap();
+        assert_eq!(
+            manifest.dests(),
+            [Path::new("b"), Path::new("d"), Path::new("f")]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_dir() {
+        let dir = temp_dir("include-dir");
+
+        // A copy of `sub/manifest` whose includes are still relative to
+        // `sub`. Nested includes stay relative to their own manifest.
+        fs::write(dir.join("copy"), "include part\n").unwrap();
+        fs::write(dir.join("sub/part"), "a\tb\ninclude more\n").unwrap();
+        fs::write(dir.join("sub/more"), "c\td\n").unwrap();
+
+        let options = ParseOptions::new().include_dir(dir.join("sub"));
+        let manifest = Manifest::parse(dir.join("copy"), &options).unwrap();
+        assert_eq!(manifest.dests(), [Path::new("b"), Path::new("d")]);
+
+        assert!(
+            Manifest::parse(dir.join("copy"), &ParseOptions::new()).is_err()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycles() {
+        let dir = temp_dir("include-cycles");
+
+        fs::write(dir.join("manifest"), "include sub/a\n").unwrap();
+        fs::write(dir.join("sub/a"), "include b\n").unwrap();
+        fs::write(dir.join("sub/b"), "x\ty\ninclude ../manifest\n").unwrap();
+
+        let options = ParseOptions::new();
+        let diagnostics =
+            Manifest::parse(dir.join("manifest"), &options).unwrap_err();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file, Some(dir.join("sub/b")));
+        assert_eq!(location(&diagnostics[0]), (2, 9, "../manifest"));
+        assert!(diagnostics[0].message.starts_with("Include cycle: "));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_directive_is_not_an_entry() {
+        assert_eq!(include_directive(b"include a b"), Some(&b"a b"[..]));
+        assert_eq!(include_directive(b"include\ta"), None);
+        assert_eq!(include_directive(b"include a\tb"), None);
+        assert_eq!(include_directive(b"includes a"), None);
+        assert_eq!(include_directive(b"include"), None);
+    }
+
+    #[test]
+    fn trees() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("sdk/include/a.h", "");
+        fs.write("sdk/include/detail/b.h", "");
+        fs.write("sdk/src/a.c", "");
+        fs.write("sdk/README", "");
+
+        let options = ParseOptions::new().dest_dir("out").filesystem(fs);
+
+        let tsv = "sdk\tsdk\tkind=tree\texclude=README\n";
+        let manifest =
+            Manifest::parse_reader(tsv.as_bytes(), &options).unwrap();
+        assert_eq!(
+            manifest.dests(),
+            [
+                Path::new("out/sdk/include/a.h"),
+                Path::new("out/sdk/include/detail/b.h"),
+                Path::new("out/sdk/src/a.c"),
+            ]
+        );
+        assert_eq!(manifest.srcs()[0], Path::new("sdk/include/a.h"));
+
+        let json = concat!(
+            r#"{"src": "sdk", "dest": "h", "kind": "tree", "#,
+            r#""include": ["*.h"]}"#,
+        );
+        let manifest =
+            Manifest::parse_reader(json.as_bytes(), &options).unwrap();
+        assert_eq!(
+            manifest.dests(),
+            [
+                Path::new("out/h/include/a.h"),
+                Path::new("out/h/include/detail/b.h")
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tree_with_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = env::temp_dir()
+            .join(format!("ubercopy-tree-links-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sdk/include")).unwrap();
+        fs::write(dir.join("sdk/include/a.h"), "").unwrap();
+
+        // Linked files are copied, but linked directories aren't walked, as
+        // this one would never end.
+        symlink("a.h", dir.join("sdk/include/b.h")).unwrap();
+        symlink("..", dir.join("sdk/include/loop")).unwrap();
+
+        let options = ParseOptions::new().src_dir(&dir).dest_dir("out");
+        let manifest =
+            Manifest::parse_reader(&b"sdk\tsdk\tkind=tree\n"[..], &options)
+                .unwrap();
+        assert_eq!(
+            manifest.dests(),
+            [
+                Path::new("out/sdk/include/a.h"),
+                Path::new("out/sdk/include/b.h"),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tree_problems() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("sdk/a", "");
+        fs.write("file", "");
+
+        let options = ParseOptions::new().filesystem(fs);
+
+        let text = "\
+missing\tx\tkind=tree
+file\tx\tkind=tree
+sdk\tx\tkind=tree\tinclude=[
+sdk\tx\tinclude=*.h
+sdk\tx\tkind=dir\tcolour=red
+";
+
+        let diagnostics = parse(text, &options);
+        let locations: Vec<_> = diagnostics.iter().map(location).collect();
+        assert_eq!(
+            locations,
+            [
+                (1, 1, "missing"),
+                (2, 1, "file"),
+                (3, 17, "include=["),
+                (4, 1, "sdk\tx\tinclude=*.h"),
+                (5, 7, "kind=dir"),
+                (5, 16, "colour=red"),
+            ]
+        );
+        assert!(diagnostics[0]
+            .message
+            .starts_with("Failed to read directory"));
+        assert!(diagnostics[2].message.starts_with("Invalid pattern"));
+    }
+
+    #[test]
+    fn globs() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("bin/a.dll", "");
+        fs.write("bin/b.dll", "");
+        fs.write("bin/b.exe", "");
+        fs.write("bin/sub/c.dll", "");
+        fs.write("lib/x64/d.lib", "");
+        fs.write("lib/x86/d.lib", "");
+        fs.write("e.txt", "");
+        fs.write("[e].txt", "");
+
+        let options = ParseOptions::new().filesystem(fs);
+
+        let text = "\
+bin/*.dll\tout/bin\tkind=glob
+lib/x*/?.lib\tout/lib\tkind=glob
+[e].txt\tout\tkind=glob
+";
+
+        let manifest =
+            Manifest::parse_reader(text.as_bytes(), &options).unwrap();
+        assert_eq!(
+            manifest.dests(),
+            [
+                Path::new("out/bin/a.dll"),
+                Path::new("out/bin/b.dll"),
+                Path::new("out/e.txt"),
+                Path::new("out/lib/d.lib"),
+                Path::new("out/lib/d.lib"),
+            ]
+        );
+
+        let json = r#"{"src": "bin/*.exe", "dest": "out", "kind": "glob"}"#;
+        let manifest =
+            Manifest::parse_reader(json.as_bytes(), &options).unwrap();
+        assert_eq!(manifest.dests(), [Path::new("out/b.exe")]);
+
+        // Without `kind=glob`, special characters are part of the name.
+        let text = "[e].txt\tout/[e].txt\n";
+        let manifest =
+            Manifest::parse_reader(text.as_bytes(), &options).unwrap();
+        assert_eq!(manifest.srcs(), [Path::new("[e].txt")]);
+        assert_eq!(manifest.dests(), [Path::new("out/[e].txt")]);
+    }
+
+    #[test]
+    fn empty_globs() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("bin/a.dll", "");
+
+        let options = ParseOptions::new().filesystem(fs);
+        let text = "\
+a\tb
+bin/*.so\tout\tkind=glob
+missing/*\tout\tkind=glob
+";
+
+        let diagnostics = parse(text, &options);
+        let locations: Vec<_> = diagnostics.iter().map(location).collect();
+        assert_eq!(locations, [(2, 1, "bin/*.so"), (3, 1, "missing/*")]);
+        assert_eq!(diagnostics[0].message, "Pattern doesn't match any files");
+
+        let options = options.allow_empty_globs(true);
+        let manifest =
+            Manifest::parse_reader(text.as_bytes(), &options).unwrap();
+        assert_eq!(manifest.dests(), [Path::new("b")]);
+    }
+}
@@ -0,0 +1,546 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! The binary format that the state of the destinations is saved in. Reading
+//! a state back needs to be fast even with millions of entries, so the copy
+//! operations are stored already sorted and with their paths exactly as they
+//! were checked and normalized by the parser.
+//!
+//! The state is an index that can be used straight from the saved bytes. A
+//! table of fixed-size records comes first, one for each copy operation, and
+//! then a pool with all of the strings. Looking up the destination of an
+//! operation is just a matter of slicing the pool, so a sync can diff against
+//! the state without decoding any of the copy operations.
+//!
+//! All integers are little-endian. The layout is:
+//!
+//!  - The magic bytes and the revision of the format as a `u32`.
+//!  - The header, as text, prefixed with its length as a `u32`.
+//!  - The number of copy operations as a `u64`.
+//!  - The records, each [`RECORD_SIZE`] bytes long.
+//!  - The length of the string pool as a `u64` and then the pool itself.
+//!
+//! A record refers to a string with its offset into the pool as a `u64` and
+//! its length as a `u32`. Each record holds, in order, the source, the
+//! destination and the hash, the offset of the first tag and the number of
+//! tags, the mode as a `u32`, the kind and the flags as one byte each and
+//! then two bytes of padding. The tags are stored one after the other in the
+//! pool, each prefixed with its length as a `u32`.
+
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::str;
+
+use crate::copyop::{CopyOp, OpKind};
+use crate::header::{Header, PREFIX};
+use crate::util;
+
+/// The start of every state file. The bytes that aren't plain text make sure
+/// that it is never mistaken for a manifest.
+pub const MAGIC: &[u8] = b"\x89UBERCOPY\r\n\x1a\n";
+
+/// The revision of the binary format.
+const REVISION: u32 = 1;
+
+/// The size of a record in bytes.
+const RECORD_SIZE: usize = 56;
+
+/// Set in the flags of a record if the operation has a hash.
+const HAS_HASH: u8 = 1;
+
+/// Set in the flags of a record if the operation has a mode.
+const HAS_MODE: u8 = 2;
+
+fn write_u32<W: Write>(writer: &mut W, n: u32) -> io::Result<()> {
+    writer.write_all(&n.to_le_bytes())
+}
+
+fn write_u64<W: Write>(writer: &mut W, n: u64) -> io::Result<()> {
+    writer.write_all(&n.to_le_bytes())
+}
+
+fn len_u32(bytes: &[u8]) -> io::Result<u32> {
+    u32::try_from(bytes.len()).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "string is too long")
+    })
+}
+
+fn path_bytes(path: &Path) -> io::Result<&[u8]> {
+    util::path_as_bytes(path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("path {:?} can't be saved", path),
+        )
+    })
+}
+
+/// Hands out the offsets of strings in the pool as they are written.
+struct Pool {
+    len: u64,
+}
+
+impl Pool {
+    /// Writes a reference to a string that will be at the end of the pool.
+    fn push<W: Write>(
+        &mut self,
+        writer: &mut W,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        write_u64(writer, self.len)?;
+        write_u32(writer, len_u32(bytes)?)?;
+        self.len += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+/// Writes out a state. The operations must already be sorted.
+pub fn write<W: Write>(
+    mut writer: W,
+    header: Option<&Header>,
+    operations: &[CopyOp],
+) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    write_u32(&mut writer, REVISION)?;
+
+    let mut text = Vec::new();
+    if let Some(header) = header {
+        header.write(&mut text)?;
+    }
+    write_u32(&mut writer, len_u32(&text)?)?;
+    writer.write_all(&text)?;
+
+    write_u64(&mut writer, operations.len() as u64)?;
+
+    // The records are written first, while working out where each string
+    // will end up in the pool. The pool is then written in the same order.
+    let mut pool = Pool { len: 0 };
+
+    for op in operations {
+        pool.push(&mut writer, path_bytes(&op.src)?)?;
+        pool.push(&mut writer, path_bytes(&op.dest)?)?;
+        pool.push(&mut writer, op.hash.as_deref().unwrap_or("").as_bytes())?;
+
+        write_u64(&mut writer, pool.len)?;
+        write_u32(&mut writer, op.tags.len() as u32)?;
+        for tag in &op.tags {
+            pool.len += 4 + tag.len() as u64;
+        }
+
+        write_u32(&mut writer, op.mode.unwrap_or(0))?;
+
+        let kind = match op.kind {
+            OpKind::Copy => 0,
+            OpKind::Symlink => 1,
+            OpKind::Hardlink => 2,
+        };
+
+        let mut flags = 0;
+        if op.hash.is_some() {
+            flags |= HAS_HASH;
+        }
+        if op.mode.is_some() {
+            flags |= HAS_MODE;
+        }
+
+        writer.write_all(&[kind, flags, 0, 0])?;
+    }
+
+    write_u64(&mut writer, pool.len)?;
+
+    for op in operations {
+        writer.write_all(path_bytes(&op.src)?)?;
+        writer.write_all(path_bytes(&op.dest)?)?;
+        writer.write_all(op.hash.as_deref().unwrap_or("").as_bytes())?;
+
+        for tag in &op.tags {
+            write_u32(&mut writer, len_u32(tag.as_bytes())?)?;
+            writer.write_all(tag.as_bytes())?;
+        }
+    }
+
+    writer.flush()
+}
+
+/// Reads the fields of a state one at a time.
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("Unexpected end of state".to_string());
+        }
+
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        usize::try_from(self.u64()?)
+            .map_err(|_| "State is too large".to_string())
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn header(&mut self) -> Result<Option<Header>, String> {
+        let text = str::from_utf8(self.bytes()?)
+            .map_err(|_| "Header isn't valid UTF-8".to_string())?;
+
+        let mut header = None;
+
+        for line in text.lines() {
+            let field = line
+                .strip_prefix(PREFIX)
+                .ok_or_else(|| "Invalid header".to_string())?;
+            Header::parse_field(&mut header, field.as_bytes())?;
+        }
+
+        Ok(header)
+    }
+}
+
+/// Where the parts of a state are, as found by [`validate`].
+#[derive(Debug)]
+pub struct Layout {
+    pub header: Option<Header>,
+    records: Range<usize>,
+    pool: Range<usize>,
+}
+
+impl Layout {
+    /// The index into the state that was validated.
+    pub fn index<'a>(&self, data: &'a [u8]) -> Index<'a> {
+        Index {
+            records: &data[self.records.clone()],
+            pool: &data[self.pool.clone()],
+        }
+    }
+}
+
+/// Checks a state written by [`write`], including the magic bytes, and finds
+/// its parts. Returns a description of the problem if it is corrupt. Once a
+/// state has been validated, nothing in its index can fail.
+pub fn validate(data: &[u8]) -> Result<Layout, String> {
+    let mut decoder = Decoder {
+        data: data
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| "Not a state file".to_string())?,
+    };
+
+    let revision = decoder.u32()?;
+    if revision != REVISION {
+        return Err(format!("Unsupported state revision {}", revision));
+    }
+
+    let header = decoder.header()?;
+
+    let count = decoder.usize()?;
+    let size = count
+        .checked_mul(RECORD_SIZE)
+        .ok_or_else(|| "State is too large".to_string())?;
+
+    let start = data.len() - decoder.data.len();
+    decoder.take(size)?;
+    let records = start..start + size;
+
+    let len = decoder.usize()?;
+    let start = data.len() - decoder.data.len();
+    decoder.take(len)?;
+    let pool = start..start + len;
+
+    if !decoder.data.is_empty() {
+        return Err("Unexpected data after the end of the state".to_string());
+    }
+
+    let layout = Layout {
+        header,
+        records,
+        pool,
+    };
+
+    let index = layout.index(data);
+    for i in 0..index.len() {
+        index.check(i)?;
+    }
+
+    Ok(layout)
+}
+
+/// The copy operations of a state, read straight from its bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct Index<'a> {
+    records: &'a [u8],
+    pool: &'a [u8],
+}
+
+impl<'a> Index<'a> {
+    /// The number of copy operations.
+    pub fn len(&self) -> usize {
+        self.records.len() / RECORD_SIZE
+    }
+
+    fn record(&self, i: usize) -> &'a [u8] {
+        &self.records[i * RECORD_SIZE..(i + 1) * RECORD_SIZE]
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+    }
+
+    /// Gets the string that field `n` of a record refers to, if it is in the
+    /// pool.
+    fn string(&self, i: usize, n: usize) -> Option<&'a [u8]> {
+        let record = self.record(i);
+        let offset = usize::try_from(Self::u64_at(record, n * 12)).ok()?;
+        let len = Self::u32_at(record, n * 12 + 8) as usize;
+        self.pool.get(offset..offset.checked_add(len)?)
+    }
+
+    fn path(&self, i: usize, n: usize) -> &'a Path {
+        self.string(i, n).and_then(util::path_from_slice).unwrap()
+    }
+
+    /// The tags of an operation as they are laid out in the pool.
+    fn tags(&self, i: usize) -> Result<Vec<&'a str>, String> {
+        let record = self.record(i);
+        let offset = usize::try_from(Self::u64_at(record, 36))
+            .map_err(|_| "Invalid tags".to_string())?;
+        let count = Self::u32_at(record, 44);
+
+        let mut decoder = Decoder {
+            data: self.pool.get(offset..).unwrap_or(&[]),
+        };
+
+        (0..count)
+            .map(|_| {
+                str::from_utf8(decoder.bytes()?)
+                    .map_err(|_| "String isn't valid UTF-8".to_string())
+            })
+            .collect()
+    }
+
+    /// Checks that a record only refers to what is in the pool.
+    fn check(&self, i: usize) -> Result<(), String> {
+        for n in 0..3 {
+            let bytes = self
+                .string(i, n)
+                .ok_or_else(|| "String is outside of the pool".to_string())?;
+
+            if n < 2 {
+                util::path_from_slice(bytes).ok_or_else(|| {
+                    "Path can't be represented here".to_string()
+                })?;
+            } else if str::from_utf8(bytes).is_err() {
+                return Err("String isn't valid UTF-8".to_string());
+            }
+        }
+
+        self.tags(i)?;
+
+        let record = self.record(i);
+        if record[52] > 2 {
+            return Err(format!("Invalid entry kind {}", record[52]));
+        }
+        if record[53] & !(HAS_HASH | HAS_MODE) != 0 {
+            return Err(format!("Invalid flags {}", record[53]));
+        }
+
+        Ok(())
+    }
+
+    /// The source of an operation.
+    pub fn src(&self, i: usize) -> &'a Path {
+        self.path(i, 0)
+    }
+
+    /// The destination of an operation.
+    pub fn dest(&self, i: usize) -> &'a Path {
+        self.path(i, 1)
+    }
+
+    /// The kind of an operation.
+    pub fn kind(&self, i: usize) -> OpKind {
+        match self.record(i)[52] {
+            0 => OpKind::Copy,
+            1 => OpKind::Symlink,
+            _ => OpKind::Hardlink,
+        }
+    }
+
+    /// Returns `true` if the operations are in order and unique, as they are
+    /// when written out by a manifest. This is checked without decoding any
+    /// of them.
+    pub fn is_sorted(&self) -> bool {
+        let key = |i| (self.dest(i), self.src(i), self.kind(i));
+        (1..self.len()).all(|i| key(i - 1) < key(i))
+    }
+
+    /// Decodes an operation.
+    pub fn op(&self, i: usize) -> CopyOp {
+        let record = self.record(i);
+        let flags = record[53];
+
+        let hash = if flags & HAS_HASH != 0 {
+            let hash = self.string(i, 2).unwrap();
+            Some(str::from_utf8(hash).unwrap().to_string())
+        } else {
+            None
+        };
+
+        let mode = if flags & HAS_MODE != 0 {
+            Some(Self::u32_at(record, 48))
+        } else {
+            None
+        };
+
+        CopyOp {
+            src: self.src(i).to_path_buf(),
+            dest: self.dest(i).to_path_buf(),
+            kind: self.kind(i),
+            hash,
+            tags: self
+                .tags(i)
+                .unwrap()
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            mode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn operations() -> Vec<CopyOp> {
+        vec![
+            CopyOp::new(PathBuf::from("a"), PathBuf::from("out/a")),
+            CopyOp {
+                kind: OpKind::Hardlink,
+                hash: Some("sha256:1234".to_string()),
+                tags: vec!["x".to_string(), "y".to_string()],
+                mode: Some(0o755),
+                ..CopyOp::new(PathBuf::from("b"), PathBuf::from("out/b"))
+            },
+        ]
+    }
+
+    fn read(data: &[u8]) -> Result<(Option<Header>, Vec<CopyOp>), String> {
+        let layout = validate(data)?;
+        let index = layout.index(data);
+        let ops = (0..index.len()).map(|i| index.op(i)).collect();
+        Ok((layout.header, ops))
+    }
+
+    #[test]
+    fn round_trip() {
+        let header = Header {
+            dest: Some(PathBuf::from("out")),
+            ..Header::new()
+        };
+
+        let mut data = Vec::new();
+        write(&mut data, Some(&header), &operations()).unwrap();
+        assert!(data.starts_with(MAGIC));
+
+        let (parsed_header, parsed) = read(&data).unwrap();
+        assert_eq!(parsed_header, Some(header));
+        // Equality ignores the hash, tags and mode.
+        assert_eq!(format!("{:?}", parsed), format!("{:?}", operations()));
+
+        let mut data = Vec::new();
+        write(&mut data, None, &[]).unwrap();
+        assert_eq!(read(&data).unwrap(), (None, vec![]));
+    }
+
+    #[test]
+    fn index() {
+        let mut data = Vec::new();
+        write(&mut data, None, &operations()).unwrap();
+
+        let index = validate(&data).unwrap().index(&data);
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.dest(1), Path::new("out/b"));
+        assert_eq!(index.src(1), Path::new("b"));
+        assert_eq!(index.kind(1), OpKind::Hardlink);
+        assert!(index.is_sorted());
+
+        // The destination is borrowed straight from the saved bytes.
+        let dest = index.dest(0) as *const Path as *const u8;
+        assert!(data.as_ptr_range().contains(&dest));
+
+        let mut ops = operations();
+        ops.reverse();
+        let mut data = Vec::new();
+        write(&mut data, None, &ops).unwrap();
+        assert!(!validate(&data).unwrap().index(&data).is_sorted());
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut data = Vec::new();
+        write(&mut data, None, &operations()).unwrap();
+
+        for len in 0..data.len() {
+            assert!(read(&data[..len]).is_err());
+        }
+
+        let mut bad = data.clone();
+        bad.push(0);
+        assert_eq!(
+            read(&bad).unwrap_err(),
+            "Unexpected data after the end of the state"
+        );
+
+        // Point the destination of the first record past the end of the
+        // pool.
+        let records = validate(&data).unwrap().records;
+        let mut bad = data.clone();
+        bad[records.start + 12..records.start + 20]
+            .copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(read(&bad).unwrap_err(), "String is outside of the pool");
+
+        let mut data = MAGIC.to_vec();
+        data.extend(&2u32.to_le_bytes());
+        assert_eq!(read(&data).unwrap_err(), "Unsupported state revision 2");
+    }
+}
@@ -20,51 +20,326 @@
 
 use scoped_pool::Pool;
 
-use crate::copyop::CopyOp;
-use crate::manifest::Manifest;
+use crate::copyop::{CopyOp, OpKind};
+use crate::filesystem::{FileSystem, RealFs};
+use crate::manifest::{self, Manifest, State};
+use crate::observer::{NoObserver, Phase, SyncObserver, Target};
 
 use crate::iter::{Change, IterExt};
-use std::fs;
+use std::collections::BTreeSet;
+use std::fmt;
 use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::error::Error;
 use crate::util;
 use crate::util::PathExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use log;
+/// Options that control how [`sync`] behaves. Use the builder methods to
+/// change the defaults:
+///
+/// ```
+/// use ubercopy::SyncOptions;
+///
+/// let options = SyncOptions::new().threads(4).retries(2).verify_copy(true);
+/// ```
+#[derive(Clone)]
+pub struct SyncOptions {
+    pub(crate) dryrun: bool,
+    pub(crate) force: bool,
+    pub(crate) verify_copy: bool,
+    pub(crate) keep_going: bool,
+    pub(crate) threads: usize,
+    pub(crate) retries: usize,
+    pub(crate) retry_delay: Duration,
+    pub(crate) observer: Arc<dyn SyncObserver>,
+    pub(crate) filesystem: Arc<dyn FileSystem>,
+    pub(crate) cancelled: Option<Arc<AtomicBool>>,
+}
+
+impl fmt::Debug for SyncOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncOptions")
+            .field("dryrun", &self.dryrun)
+            .field("force", &self.force)
+            .field("verify_copy", &self.verify_copy)
+            .field("keep_going", &self.keep_going)
+            .field("threads", &self.threads)
+            .field("retries", &self.retries)
+            .field("retry_delay", &self.retry_delay)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            dryrun: false,
+            force: false,
+            verify_copy: false,
+            keep_going: false,
+            threads: 20,
+            retries: 5,
+            retry_delay: Duration::from_secs(1),
+            observer: Arc::new(NoObserver),
+            filesystem: Arc::new(RealFs),
+            cancelled: None,
+        }
+    }
+}
+
+impl SyncOptions {
+    pub fn new() -> Self {
+        SyncOptions::default()
+    }
+
+    /// Don't actually do anything. Just log what might happen.
+    pub fn dryrun(mut self, dryrun: bool) -> Self {
+        self.dryrun = dryrun;
+        self
+    }
+
+    /// Copy everything regardless of whether or not it is out of date.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// After copying, verify that all files match.
+    pub fn verify_copy(mut self, verify_copy: bool) -> Self {
+        self.verify_copy = verify_copy;
+        self
+    }
+
+    /// Keep going after a phase fails. Only the operations affected by the
+    /// failure are skipped and all of the failures are reported together at
+    /// the end.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Number of threads to use for deleting, checking and copying files.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Number of times to retry a copy or deletion before giving up.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// How long to wait before the first retry. The delay doubles with each
+    /// subsequent retry.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Receives events as the sync progresses.
+    pub fn observer(mut self, observer: Arc<dyn SyncObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// The file system to sync. This is the real file system by default.
+    pub fn filesystem(mut self, filesystem: Arc<dyn FileSystem>) -> Self {
+        self.filesystem = filesystem;
+        self
+    }
+
+    /// Returns an error if the sync has been cancelled. This is checked before
+    /// each operation so that a cancelled sync winds down quickly.
+    pub(crate) fn check_cancelled(&self) -> io::Result<()> {
+        match self.cancelled {
+            Some(ref cancelled) if cancelled.load(Ordering::SeqCst) => {
+                Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "sync was cancelled",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Takes ownership of the copy operations that failed so that they can be
+/// returned in an `Error`.
+fn owned(errors: Vec<(&CopyOp, io::Error)>) -> Vec<(CopyOp, io::Error)> {
+    errors
+        .into_iter()
+        .map(|(op, err)| (op.clone(), err))
+        .collect()
+}
+
+/// The errors from each failed phase.
+struct Failures {
+    keep_going: bool,
+    cancelled: Option<Arc<AtomicBool>>,
+    errors: Vec<Error>,
+}
+
+impl Failures {
+    fn new(options: &SyncOptions) -> Self {
+        Failures {
+            keep_going: options.keep_going,
+            cancelled: options.cancelled.clone(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled
+            .as_ref()
+            .is_some_and(|cancelled| cancelled.load(Ordering::SeqCst))
+    }
+
+    /// Records a failed phase. Unless we're keeping going, this returns an
+    /// error to stop the sync.
+    fn push(&mut self, err: Error) -> Result<(), Error> {
+        // The operations that a cancellation stopped part way through a phase
+        // fail as interrupted. Only the cancellation itself is worth
+        // reporting.
+        self.check_cancelled()?;
+
+        self.errors.push(err);
+
+        if self.keep_going {
+            Ok(())
+        } else {
+            Err(self.take())
+        }
+    }
+
+    /// Returns an error if the sync has been cancelled. This is checked
+    /// between phases.
+    fn check_cancelled(&mut self) -> Result<(), Error> {
+        if self.is_cancelled() {
+            self.errors.push(Error::Cancelled);
+            return Err(self.take());
+        }
+
+        Ok(())
+    }
+
+    /// Returns `value` if nothing failed.
+    fn finish<T>(mut self, value: T) -> Result<T, Error> {
+        if self.errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(self.take())
+        }
+    }
+
+    fn take(&mut self) -> Error {
+        let mut errors = mem::take(&mut self.errors);
+
+        if errors.len() == 1 {
+            errors.pop().unwrap()
+        } else {
+            Error::Multiple(errors)
+        }
+    }
+}
+
+/// The operations that a sync has actually carried out.
+#[derive(Default)]
+struct Progress<'a> {
+    /// Destinations that were deleted.
+    deleted: BTreeSet<&'a Path>,
+
+    /// Copy operations that are known to be complete.
+    complete: Vec<&'a CopyOp>,
+}
+
+impl<'a> Progress<'a> {
+    /// Returns a filter for the destinations of the previous state that are
+    /// still around as they were before the sync.
+    fn unchanged(&self) -> impl Fn(&Path) -> bool + '_ {
+        let complete: BTreeSet<&Path> =
+            self.complete.iter().map(|op| op.dest.as_path()).collect();
+
+        move |dest| !self.deleted.contains(dest) && !complete.contains(dest)
+    }
+
+    /// The state of the destinations given the copy operations of the
+    /// previous state that passed the `unchanged` filter.
+    fn state(&self, unchanged: Vec<CopyOp>) -> Manifest {
+        unchanged
+            .into_iter()
+            .chain(self.complete.iter().cloned().cloned())
+            .collect()
+    }
+}
+
+/// Returns the errors for any race conditions. Assumes `next_srcs` and
+/// `next_dests` are sorted.
+fn check_races(next_srcs: &[&Path], next_dests: &[&Path]) -> Vec<Error> {
+    let mut errors = Vec::new();
 
-/// Returns an Error result if there are race conditions. Assumes `next_srcs`
-/// and `next_dests` are sorted.
-fn check_races<'a>(
-    next_srcs: &[&'a Path],
-    next_dests: &[&'a Path],
-) -> Result<(), Error<'a>> {
     let overlap: Vec<_> = next_srcs
         .iter()
         .changes(next_dests.iter())
-        .filter(|&(_, ref c)| c == &Change::None)
-        .map(|(e, _)| *e)
+        .filter(|(_, c)| c == &Change::None)
+        .map(|(e, _)| e.to_path_buf())
         .collect();
 
     if !overlap.is_empty() {
-        return Err(Error::Overlap(overlap));
+        errors.push(Error::Overlap(overlap));
     }
 
     let duplicates: Vec<_> = next_dests
         .iter()
         .adjacent()
-        .filter(|&(_, ref count)| *count > 1) // Duplicates
-        .map(|(e, ref count)| (*e, *count))
+        .filter(|(_, count)| *count > 1) // Duplicates
+        .map(|(e, count)| (e.to_path_buf(), count))
         .collect();
 
     if !duplicates.is_empty() {
-        return Err(Error::Duplicates(duplicates));
+        errors.push(Error::Duplicates(duplicates));
+    }
+
+    errors
+}
+
+/// Splits copy operations into batches that must be done one after the other.
+/// Hard links come after everything else, as they may link to another
+/// destination. A link to another link comes after the link it points to.
+fn batches<'a>(ops: &[&'a CopyOp]) -> Vec<Vec<&'a CopyOp>> {
+    let (mut links, copies): (Vec<&CopyOp>, Vec<&CopyOp>) = ops
+        .iter()
+        .copied()
+        .partition(|op| op.kind == OpKind::Hardlink);
+
+    let mut batches = vec![copies];
+
+    while !links.is_empty() {
+        let pending: BTreeSet<&Path> =
+            links.iter().map(|op| op.dest.as_path()).collect();
+
+        let (later, now): (Vec<&CopyOp>, Vec<&CopyOp>) = links
+            .into_iter()
+            .partition(|op| pending.contains(op.src.as_path()));
+
+        if now.is_empty() {
+            // The links form a cycle. They will fail, but that's reported
+            // like any other failure.
+            batches.push(later);
+            break;
+        }
+
+        batches.push(now);
+        links = later;
     }
 
-    Ok(())
+    batches
 }
 
 /// Synchronizes the file system with the `next` manifest. The `prev` manifest
@@ -74,15 +349,15 @@ fn check_races<'a>(
 /// The synchronization takes place in several phases:
 ///
 ///  1. Check for race conditions.
-///     (a) Check for overlap between the source and destination files in the
-///         `next` manifest.
-///     (b) Check for destination paths that have been duplicated in the `next`
-///         manifest.
+///     - Check for overlap between the source and destination files in the
+///       `next` manifest.
+///     - Check for destination paths that have been duplicated in the `next`
+///       manifest.
 ///  2. Compare the destinations of `prev` with that of `next` to see which ones
 ///     need to be deleted from disk.
-///     (a) For each of the files that needs to be deleted.
-///     (b) Get the parent directory for each file and delete as much as we can.
-///         `rmdir` will fail if a directory isn't empty.
+///     - For each of the files that needs to be deleted.
+///     - Get the parent directory for each file and delete as much as we can.
+///       `rmdir` will fail if a directory isn't empty.
 ///  3. Compare the timestamps of the source and destination paths in `next` to
 ///     build up a list of copy operations that need to occur. If `--force` was
 ///     specified, this list should simply be the entire list in the manifest.
@@ -90,41 +365,140 @@ fn check_races<'a>(
 ///  4. Create parent directories for each file.
 ///  5. Go through the list in #3 and do the copy. Build up a list of the
 ///     failures and report the error.
-///  6. Do a sanity check (if `sanity == true`) to make sure all timestamps are
-///     equal and that all files exist. This is to help catch bugs in this
+///  6. Do a sanity check (if `verify_copy` is set) to make sure all timestamps
+///     are equal and that all files exist. This is to help catch bugs in this
 ///     program.
-#[allow(clippy::too_many_arguments)]
-pub fn sync<'a>(
-    prev: &'a Manifest,
+///
+/// If `keep_going` is set, a failed phase doesn't stop the sync. Instead, the
+/// later phases skip only the operations affected by the failure and all of the
+/// errors are returned together at the end.
+pub fn sync(
+    prev: &Manifest,
+    next: &Manifest,
+    options: &SyncOptions,
+) -> Result<usize, Error> {
+    run(prev.dests(), next, options, &mut Progress::default())
+}
+
+/// Like [`sync`], but also returns the state of the destinations afterwards.
+/// This is `next` if the sync succeeded. Otherwise, it is made up of the copy
+/// operations from `next` that completed and the ones from `prev` whose
+/// destinations are still around. Using this as the `prev` manifest for the
+/// next sync means that it starts from what is really on disk.
+pub fn sync_with_state(
+    prev: &Manifest,
+    next: &Manifest,
+    options: &SyncOptions,
+) -> (Manifest, Result<usize, Error>) {
+    let mut progress = Progress::default();
+
+    let result = run(prev.dests(), next, options, &mut progress);
+
+    let state: Manifest = if result.is_ok() && !options.dryrun {
+        next.operations().iter().cloned().collect()
+    } else {
+        let unchanged = progress.unchanged();
+        progress.state(
+            prev.operations()
+                .iter()
+                .filter(|op| unchanged(&op.dest))
+                .cloned()
+                .collect(),
+        )
+    };
+
+    (state.with_header(next.header().cloned()), result)
+}
+
+/// Like [`sync_with_state`], but diffs against a [`State`] saved by an
+/// earlier sync. Only the destinations of `prev` are looked at, unless the
+/// sync fails and some of its copy operations have to be carried over to the
+/// new state.
+pub fn sync_from_state(
+    prev: &State,
+    next: &Manifest,
+    options: &SyncOptions,
+) -> (Manifest, Result<usize, Error>) {
+    let mut progress = Progress::default();
+
+    let result = run(prev.dests(), next, options, &mut progress);
+
+    let state: Manifest = if result.is_ok() && !options.dryrun {
+        next.operations().iter().cloned().collect()
+    } else {
+        progress.state(prev.operations_where(progress.unchanged()))
+    };
+
+    (state.with_header(next.header().cloned()), result)
+}
+
+fn run<'a>(
+    prev_dests: Vec<&'a Path>,
     next: &'a Manifest,
-    dryrun: bool,
-    force: bool,
-    verify_copy: bool,
-    threads: usize,
-    retries: usize,
-    retry_delay: Duration,
-) -> Result<usize, Error<'a>> {
+    options: &SyncOptions,
+    progress: &mut Progress<'a>,
+) -> Result<usize, Error> {
+    let SyncOptions {
+        dryrun,
+        force,
+        verify_copy,
+        threads,
+        retries,
+        retry_delay,
+        ..
+    } = *options;
+
+    let observer = options.observer.as_ref();
+    let fs = options.filesystem.as_ref();
+
+    let mut failures = Failures::new(options);
+
     log::info!("Creating thread pool with {} threads", threads);
 
     let pool = Pool::new(threads);
 
-    let prev_dests = prev.dests();
     let next_srcs = next.srcs();
     let next_dests = next.dests();
 
     // 1. Check for race conditions.
     log::info!("Checking for race conditions");
-    check_races(&next_srcs, &next_dests)?;
+    observer.phase_start(Phase::CheckRaces);
+
+    // Paths involved in a race. Copy operations touching these are skipped.
+    let mut racy: BTreeSet<PathBuf> = BTreeSet::new();
+
+    for err in check_races(&next_srcs, &next_dests) {
+        match err {
+            Error::Overlap(ref paths) => racy.extend(paths.iter().cloned()),
+            Error::Duplicates(ref paths) => {
+                racy.extend(paths.iter().map(|(path, _)| path.clone()))
+            }
+            _ => {}
+        };
+
+        failures.push(err)?;
+    }
+
+    observer.phase_end(Phase::CheckRaces);
+
+    let ops: Vec<&CopyOp> = next
+        .operations()
+        .iter()
+        .filter(|op| !racy.contains(&op.src) && !racy.contains(&op.dest))
+        .collect();
 
     // 2. Compare the destinations of `prev` with that of `next` to see which
     //    ones need to be deleted from disk.
     let to_delete: Vec<&Path> = prev_dests
         .iter()
         .changes(next_dests.iter())
-        .filter(|&(_, ref c)| c == &Change::Removed)
+        .filter(|(_, c)| c == &Change::Removed)
         .map(|(e, _)| *e)
         .collect();
 
+    failures.check_cancelled()?;
+    observer.phase_start(Phase::Delete);
+
     if dryrun {
         for f in &to_delete {
             log::debug!("Deleting destination {:?}", f);
@@ -139,32 +513,51 @@ pub fn sync<'a>(
 
                 let tx = tx.clone();
                 scope.execute(move || {
-                    tx.send((
-                        *f,
-                        util::remove_file_retry(f, retries, retry_delay),
-                    ))
-                    .unwrap();
+                    let result = options.check_cancelled().and_then(|()| {
+                        util::remove_file_retry(fs, f, retries, retry_delay)
+                    });
+
+                    match result {
+                        Ok(()) => observer.deleted(f),
+                        Err(ref err) => {
+                            observer.failed(Phase::Delete, Target::Path(f), err)
+                        }
+                    };
+
+                    tx.send((*f, result)).unwrap();
                 });
             }
 
-            let mut failed: Vec<(&'a Path, io::Error)> = Vec::new();
+            // If a job panics, it never sends its result. Once every sender
+            // is gone, the loop ends early and the panic is propagated.
+            drop(tx);
+
+            let mut failed: Vec<(PathBuf, io::Error)> = Vec::new();
 
             for (f, result) in rx.iter().take(to_delete.len()) {
-                if let Err(err) = result {
-                    failed.push((f, err));
-                }
+                match result {
+                    Ok(()) => {
+                        progress.deleted.insert(f);
+                    }
+                    Err(err) => failed.push((f.to_path_buf(), err)),
+                };
             }
 
             failed
         });
 
         if !failed.is_empty() {
-            return Err(Error::Delete(failed));
+            failures.push(Error::Delete(failed))?;
         }
     }
 
+    observer.phase_end(Phase::Delete);
+
     {
-        let mut failed: Vec<(&Path, io::Error)> = Vec::new();
+        failures.check_cancelled()?;
+        observer.phase_start(Phase::DeleteDirs);
+
+        let mut failed: Vec<(PathBuf, io::Error)> = Vec::new();
 
         // Try deleting parent directories as well.
         let parent_dirs = to_delete
@@ -176,30 +569,71 @@ pub fn sync<'a>(
             log::debug!("Deleting directory {:?}", dir);
 
             if !dryrun {
-                if let Err(error) =
-                    util::remove_empty_dirs(dir, retries, retry_delay)
-                {
-                    failed.push((dir, error));
+                if let Err(error) = util::remove_empty_dirs(
+                    fs,
+                    dir,
+                    retries,
+                    retry_delay,
+                    &|removed| observer.dir_deleted(removed),
+                ) {
+                    observer.failed(
+                        Phase::DeleteDirs,
+                        Target::Path(dir),
+                        &error,
+                    );
+                    failed.push((dir.to_path_buf(), error));
                 }
             }
         }
 
         if !failed.is_empty() {
-            return Err(Error::DeleteDirs(failed));
+            failures.push(Error::DeleteDirs(failed))?;
         }
+
+        observer.phase_end(Phase::DeleteDirs);
     }
 
+    // Destinations that won't be brought up-to-date because of an earlier
+    // failure. These are left out of the verification.
+    let mut unfinished: BTreeSet<&Path> = BTreeSet::new();
+
     // 3. Filter the manifest for files that need to be copied.
-    let outdated = next.outdated(force, &pool, retries, retry_delay);
+    failures.check_cancelled()?;
+    observer.phase_start(Phase::Outdated);
+
+    let (mut outdated, errors) =
+        manifest::outdated(&ops, force, &pool, options);
 
-    if let Err(errors) = outdated {
-        return Err(Error::MissingSrcs(errors));
+    for (op, err) in &errors {
+        observer.failed(Phase::Outdated, Target::Op(op), err);
+        unfinished.insert(&op.dest);
     }
 
-    let outdated = outdated.unwrap();
+    // Whatever is already up-to-date is recorded before any error is
+    // returned so that the state doesn't forget about it.
+    if !dryrun {
+        let pending: BTreeSet<&Path> = outdated
+            .iter()
+            .map(|op| op.dest.as_path())
+            .chain(unfinished.iter().cloned())
+            .collect();
+
+        progress.complete.extend(
+            ops.iter().filter(|op| !pending.contains(op.dest.as_path())),
+        );
+    }
+
+    if !errors.is_empty() {
+        failures.push(Error::MissingSrcs(owned(errors)))?;
+    }
+
+    observer.phase_end(Phase::Outdated);
 
     {
         // 4. Create parent directories for modified files.
+        failures.check_cancelled()?;
+        observer.phase_start(Phase::CreateDirs);
+
         let mut dirs: Vec<&Path> = outdated
             .iter()
             .filter_map(|op| op.dest.removable_parent())
@@ -209,74 +643,902 @@ pub fn sync<'a>(
 
         let dirs: Vec<&Path> = dirs.iter().unique().cloned().collect();
 
-        let mut failed: Vec<(&'a Path, io::Error)> = Vec::new();
+        let mut failed: Vec<(PathBuf, io::Error)> = Vec::new();
 
         for dir in dirs {
             log::debug!("Creating directory {:?}", dir);
 
             if !dryrun {
-                if let Err(err) = fs::create_dir_all(dir) {
-                    failed.push((dir, err));
-                }
+                match fs.create_dir_all(dir) {
+                    Ok(()) => observer.dir_created(dir),
+                    Err(err) => {
+                        observer.failed(
+                            Phase::CreateDirs,
+                            Target::Path(dir),
+                            &err,
+                        );
+                        failed.push((dir.to_path_buf(), err));
+                    }
+                };
             }
         }
 
         if !failed.is_empty() {
-            return Err(Error::CreateDirs(failed));
+            // There's no point in copying into directories that don't exist.
+            outdated.retain(|op| {
+                let skip =
+                    failed.iter().any(|(dir, _)| op.dest.starts_with(dir));
+
+                if skip {
+                    unfinished.insert(&op.dest);
+                }
+
+                !skip
+            });
+
+            failures.push(Error::CreateDirs(failed))?;
         }
+
+        observer.phase_end(Phase::CreateDirs);
     }
 
     // 5. Do the actual copy.
     log::info!("Copying files...");
+    failures.check_cancelled()?;
+    observer.phase_start(Phase::Copy);
+
+    let mut copied = outdated.len();
 
     if dryrun {
         for op in &outdated {
             log::debug!("Copying {}", op);
         }
     } else {
-        let (tx, rx) = sync_channel(32);
+        let mut failed: Vec<(&CopyOp, io::Error)> = Vec::new();
 
-        let failed = pool.scoped(|scope| {
-            for op in &outdated {
-                log::debug!("Copying {}", op);
+        for batch in batches(&outdated) {
+            let (tx, rx) = sync_channel(32);
 
-                let tx = tx.clone();
+            pool.scoped(|scope| {
+                for op in &batch {
+                    log::debug!("Copying {}", op);
 
-                scope.execute(move || {
-                    tx.send((*op, op.copy(retries, retry_delay))).unwrap();
-                });
-            }
+                    let tx = tx.clone();
+
+                    scope.execute(move || {
+                        observer.copy_start(op);
 
-            let mut failed: Vec<(&CopyOp, io::Error)> = Vec::new();
+                        let mut report = |n| observer.copy_progress(op, n);
+                        let progress = if observer.wants_progress() {
+                            Some(&mut report as &mut dyn FnMut(u64))
+                        } else {
+                            None
+                        };
 
-            for (op, result) in rx.iter().take(outdated.len()) {
-                if let Err(err) = result {
-                    failed.push((op, err));
+                        let result = options.check_cancelled().and_then(|()| {
+                            op.copy(fs, retries, retry_delay, progress)
+                        });
+
+                        match result {
+                            Ok(_) => observer.copy_finished(op),
+                            Err(ref err) => observer.failed(
+                                Phase::Copy,
+                                Target::Op(op),
+                                err,
+                            ),
+                        };
+
+                        tx.send((*op, result)).unwrap();
+                    });
                 }
-            }
 
-            failed
-        });
+                drop(tx);
+
+                for (op, result) in rx.iter().take(batch.len()) {
+                    match result {
+                        Ok(_) => progress.complete.push(op),
+                        Err(err) => failed.push((op, err)),
+                    };
+                }
+            });
+        }
 
         if !failed.is_empty() {
-            return Err(Error::Copy(failed));
+            copied -= failed.len();
+
+            for (op, _) in &failed {
+                unfinished.insert(&op.dest);
+            }
+
+            failures.push(Error::Copy(owned(failed)))?;
         }
     }
 
+    observer.phase_end(Phase::Copy);
+
     // 6. Verify all files have been copied successfully.
     if verify_copy && !dryrun {
         log::info!("Performing post-copy verification");
+        failures.check_cancelled()?;
+        observer.phase_start(Phase::Verify);
+
+        let ops: Vec<&CopyOp> = ops
+            .into_iter()
+            .filter(|op| !unfinished.contains(op.dest.as_path()))
+            .collect();
 
         // There should be *no* outdated files at this point.
-        match next.outdated(false, &pool, retries, retry_delay) {
-            Ok(ops) => {
-                if !ops.is_empty() {
-                    return Err(Error::VerifyIncomplete(ops));
-                }
+        let (incomplete, errors) =
+            manifest::outdated(&ops, false, &pool, options);
+
+        if !errors.is_empty() {
+            for (op, err) in &errors {
+                observer.failed(Phase::Verify, Target::Op(op), err);
             }
-            Err(errors) => return Err(Error::VerifyErrors(errors)),
+
+            failures.push(Error::VerifyErrors(owned(errors)))?;
+        }
+
+        if !incomplete.is_empty() {
+            failures.push(Error::VerifyIncomplete(
+                incomplete.into_iter().cloned().collect(),
+            ))?;
+        }
+
+        observer.phase_end(Phase::Verify);
+    }
+
+    failures.finish(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::ErrorKind;
+    use crate::faultfs::{FaultFs, Operation};
+    use crate::memfs::MemoryFs;
+    use crate::parser::ParseOptions;
+
+    use std::sync::Mutex;
+
+    fn manifest(text: &str) -> Manifest {
+        Manifest::parse_reader(text.as_bytes(), &ParseOptions::new()).unwrap()
+    }
+
+    fn options(fs: Arc<dyn FileSystem>) -> SyncOptions {
+        SyncOptions::new()
+            .threads(2)
+            .retries(0)
+            .retry_delay(Duration::ZERO)
+            .verify_copy(true)
+            .filesystem(fs)
+    }
+
+    /// Records the events of a sync in the order that they happen.
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Recorder {
+        fn push(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    impl SyncObserver for Recorder {
+        fn phase_start(&self, phase: Phase) {
+            self.push(format!("start {:?}", phase));
+        }
+
+        fn phase_end(&self, phase: Phase) {
+            self.push(format!("end {:?}", phase));
+        }
+
+        fn deleted(&self, path: &Path) {
+            self.push(format!("deleted {}", path.display()));
+        }
+
+        fn dir_deleted(&self, path: &Path) {
+            self.push(format!("dir deleted {}", path.display()));
+        }
+
+        fn dir_created(&self, path: &Path) {
+            self.push(format!("dir created {}", path.display()));
+        }
+
+        fn copy_start(&self, op: &CopyOp) {
+            self.push(format!("copy start {}", op.dest.display()));
+        }
+
+        fn copy_progress(&self, op: &CopyOp, copied: u64) {
+            self.push(format!(
+                "copy progress {} {}",
+                op.dest.display(),
+                copied
+            ));
+        }
+
+        fn copy_finished(&self, op: &CopyOp) {
+            self.push(format!("copy finished {}", op.dest.display()));
+        }
+
+        fn failed(&self, phase: Phase, target: Target<'_>, _: &io::Error) {
+            let path = match target {
+                Target::Path(path) => path,
+                Target::Op(op) => &op.dest,
+            };
+
+            self.push(format!("failed {:?} {}", phase, path.display()));
+        }
+    }
+
+    #[test]
+    fn observer_events() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "aa");
+        fs.inner().write("src/b", "b");
+        fs.inner().write("dest/old/x", "x");
+        fs.inner().write("dest/keep", "");
+        fs.fail(Operation::Copy, "src/b", 1, io::ErrorKind::PermissionDenied);
+
+        let recorder = Arc::new(Recorder::default());
+        let options = options(fs.clone())
+            .threads(1)
+            .keep_going(true)
+            .observer(recorder.clone());
+
+        // The parent of the missing destination doesn't exist, so it isn't
+        // reported as deleted.
+        let prev = manifest("src/x\tdest/old/x\nsrc/y\tdest/gone/y\n");
+        let next = manifest("src/a\tdest/new/a\nsrc/b\tdest/new/b\n");
+        sync(&prev, &next, &options).unwrap_err();
+
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [
+                "start CheckRaces",
+                "end CheckRaces",
+                "start Delete",
+                "deleted dest/gone/y",
+                "deleted dest/old/x",
+                "end Delete",
+                "start DeleteDirs",
+                "dir deleted dest/old",
+                "end DeleteDirs",
+                "start Outdated",
+                "end Outdated",
+                "start CreateDirs",
+                "dir created dest/new",
+                "end CreateDirs",
+                "start Copy",
+                "copy start dest/new/a",
+                "copy progress dest/new/a 2",
+                "copy finished dest/new/a",
+                "copy start dest/new/b",
+                "failed Copy dest/new/b",
+                "end Copy",
+                "start Verify",
+                "end Verify",
+            ]
+        );
+    }
+
+    /// Cancels the sync as soon as the first copy starts.
+    struct CancelOnCopy(Arc<AtomicBool>);
+
+    impl SyncObserver for CancelOnCopy {
+        fn copy_start(&self, _op: &CopyOp) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn cancelled_during_a_phase() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("src/a", "a");
+        fs.write("src/b", "b");
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut options = options(fs.clone())
+            .keep_going(true)
+            .observer(Arc::new(CancelOnCopy(cancelled.clone())));
+        options.cancelled = Some(cancelled);
+
+        // The interrupted copies aren't reported as failures of their own.
+        let next = manifest("src/a\tdest/a\nsrc/b\tdest/b\n");
+        let err = sync(&Manifest::new(), &next, &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Cancelled);
+        assert!(!fs.exists("dest/a"));
+    }
+
+    #[test]
+    fn cancelled_copies_keep_destinations() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("src/a", "a");
+        fs.write("src/b", "b");
+
+        // One destination is from the last sync and the other was already
+        // there, but neither has been copied yet.
+        let prev = manifest("src/a\tdest/a\n");
+        fs.write("dest/a", "old a");
+        fs.write("dest/b", "old b");
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut options = options(fs.clone())
+            .threads(1)
+            .keep_going(true)
+            .observer(Arc::new(CancelOnCopy(cancelled.clone())));
+        options.cancelled = Some(cancelled);
+
+        let next = manifest("src/a\tdest/a\nsrc/b\tdest/b\n");
+        let err = sync(&prev, &next, &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Cancelled);
+        assert_eq!(fs.read("dest/a").unwrap(), b"old a");
+        assert_eq!(fs.read("dest/b").unwrap(), b"old b");
+    }
+
+    #[test]
+    fn copies_outdated_files() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("src/a", "a");
+        fs.write("src/b", "b");
+
+        let prev = Manifest::new();
+        let next = manifest("src/a\tdest/a\nsrc/b\tdest/sub/b\n");
+
+        assert_eq!(sync(&prev, &next, &options(fs.clone())).unwrap(), 2);
+        assert_eq!(fs.read("dest/a").unwrap(), b"a");
+        assert_eq!(fs.read("dest/sub/b").unwrap(), b"b");
+
+        // Nothing has changed, so nothing should get copied.
+        assert_eq!(sync(&next, &next, &options(fs.clone())).unwrap(), 0);
+
+        fs.write("src/b", "bb");
+        assert_eq!(sync(&next, &next, &options(fs.clone())).unwrap(), 1);
+        assert_eq!(fs.read("dest/sub/b").unwrap(), b"bb");
+    }
+
+    #[test]
+    fn copies_names_with_special_characters() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("src/[e].txt", "e");
+        fs.write("src/e.txt", "not e");
+
+        let next = manifest("src/[e].txt\tdest/[e].txt\n");
+        assert_eq!(
+            sync(&Manifest::new(), &next, &options(fs.clone())).unwrap(),
+            1
+        );
+        assert_eq!(fs.read("dest/[e].txt").unwrap(), b"e");
+    }
+
+    #[test]
+    fn fixes_mode_drift_without_copying() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/run.sh", "#!/bin/sh");
+
+        let next = manifest("src/run.sh\tdest/run.sh\tmode=0755\n");
+        let options = options(fs.clone());
+
+        assert_eq!(sync(&Manifest::new(), &next, &options).unwrap(), 1);
+        assert_eq!(
+            fs.inner().metadata(Path::new("dest/run.sh")).unwrap().mode,
+            0o755
+        );
+        assert_eq!(fs.calls(Operation::Copy, "src/run.sh"), 1);
+
+        // Only the mode is fixed up if it drifts.
+        fs.set_mode(Path::new("dest/run.sh"), 0o644).unwrap();
+        assert_eq!(sync(&next, &next, &options).unwrap(), 1);
+        assert_eq!(
+            fs.inner().metadata(Path::new("dest/run.sh")).unwrap().mode,
+            0o755
+        );
+        assert_eq!(fs.calls(Operation::Copy, "src/run.sh"), 1);
+
+        assert_eq!(sync(&next, &next, &options).unwrap(), 0);
+    }
+
+    #[test]
+    fn symlinks() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("lib/libfoo.so.1.2", "foo");
+
+        let v1 = manifest(
+            "lib/libfoo.so.1.2\tout/libfoo.so.1.2
+libfoo.so.1.2\tout/libfoo.so\tkind=symlink
+",
+        );
+
+        // The target of a link is not a source, so it can't overlap with a
+        // destination.
+        assert_eq!(
+            sync(&Manifest::new(), &v1, &options(fs.clone())).unwrap(),
+            2
+        );
+        assert_eq!(
+            fs.read_link(Path::new("out/libfoo.so")).unwrap(),
+            Path::new("libfoo.so.1.2")
+        );
+        assert_eq!(fs.read("out/libfoo.so").unwrap(), b"foo");
+        assert_eq!(sync(&v1, &v1, &options(fs.clone())).unwrap(), 0);
+
+        // A link that points somewhere else is recreated.
+        fs.remove_file(Path::new("out/libfoo.so")).unwrap();
+        fs.symlink(Path::new("elsewhere"), Path::new("out/libfoo.so"))
+            .unwrap();
+        assert_eq!(sync(&v1, &v1, &options(fs.clone())).unwrap(), 1);
+        assert_eq!(
+            fs.read_link(Path::new("out/libfoo.so")).unwrap(),
+            Path::new("libfoo.so.1.2")
+        );
+
+        let v2 = manifest("lib/libfoo.so.1.2\tout/libfoo.so.1.2\n");
+        sync(&v1, &v2, &options(fs.clone())).unwrap();
+        assert!(!fs.exists("out/libfoo.so"));
+    }
+
+    #[test]
+    fn hardlinks() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/big", "big");
+
+        // The second link is to another destination, which has to be copied
+        // first.
+        let next = manifest(
+            "src/big\tout/a/big\tkind=hardlink
+out/a/big\tout/b/big\tkind=hardlink
+src/big\tout/c/big
+",
+        );
+        let options = options(fs.clone());
+
+        assert_eq!(sync(&Manifest::new(), &next, &options).unwrap(), 3);
+
+        let id = |path: &str| fs.metadata(Path::new(path)).unwrap().id;
+        assert_eq!(id("out/a/big"), id("src/big"));
+        assert_eq!(id("out/b/big"), id("src/big"));
+        assert_ne!(id("out/c/big"), id("src/big"));
+        assert_eq!(sync(&next, &next, &options).unwrap(), 0);
+
+        // A link that can't be made because it crosses devices becomes a
+        // copy. The link to it is made again as well. Everything is on the
+        // same device here, so this wouldn't pass verification.
+        fs.remove_file(Path::new("out/a/big")).unwrap();
+        fs.fail(
+            Operation::HardLink,
+            "out/a/big",
+            1,
+            io::ErrorKind::CrossesDevices,
+        );
+        let options = options.verify_copy(false);
+        assert_eq!(sync(&next, &next, &options).unwrap(), 2);
+        assert_eq!(fs.inner().read("out/a/big").unwrap(), b"big");
+        assert_ne!(id("out/a/big"), id("src/big"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hardlink_becomes_copy() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = std::env::temp_dir()
+            .join(format!("ubercopy-relink-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a"), "aaa").unwrap();
+        std::fs::write(dir.join("c"), "c").unwrap();
+
+        let options = options(Arc::new(RealFs));
+        let entry = |src: &str, kind: &str| {
+            manifest(&format!(
+                "{}\t{}{}\n",
+                dir.join(src).display(),
+                dir.join("b").display(),
+                kind
+            ))
+        };
+        let link = entry("a", "\tkind=hardlink");
+        sync(&Manifest::new(), &link, &options).unwrap();
+
+        // Copying over the link must not write through to its source.
+        let copy = entry("c", "");
+        assert_eq!(sync(&link, &copy, &options).unwrap(), 1);
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"aaa");
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), b"c");
+
+        // A link to the same source isn't a complete copy of it.
+        sync(&copy, &link, &options).unwrap();
+        let same = entry("a", "");
+        assert_eq!(sync(&link, &same, &options).unwrap(), 1);
+        let ino = |name| std::fs::metadata(dir.join(name)).unwrap().ino();
+        assert_ne!(ino("a"), ino("b"));
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), b"aaa");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_becomes_copy() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir()
+            .join(format!("ubercopy-unlink-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a"), "aaa").unwrap();
+        std::fs::write(dir.join("c"), "c").unwrap();
+
+        let options = options(Arc::new(RealFs));
+        let entry = |src: &str, kind: &str| {
+            manifest(&format!(
+                "{}\t{}{}\n",
+                dir.join(src).display(),
+                dir.join("b").display(),
+                kind
+            ))
+        };
+        let link = entry("a", "\tkind=symlink");
+        sync(&Manifest::new(), &link, &options).unwrap();
+
+        // Copying over the link must not write through to its target.
+        let copy = entry("c", "\tmode=0600");
+        assert_eq!(sync(&link, &copy, &options).unwrap(), 1);
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"aaa");
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), b"c");
+        let mode = |name| {
+            std::fs::metadata(dir.join(name))
+                .unwrap()
+                .permissions()
+                .mode()
+        };
+        assert_ne!(mode("a") & 0o777, 0o600);
+        assert_eq!(mode("b") & 0o777, 0o600);
+        assert!(!std::fs::symlink_metadata(dir.join("b"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+
+        // A link to the same source isn't a complete copy of it.
+        sync(&copy, &link, &options).unwrap();
+        let same = entry("a", "");
+        assert_eq!(sync(&link, &same, &options).unwrap(), 1);
+        assert!(!std::fs::symlink_metadata(dir.join("b"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), b"aaa");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deletes_removed_destinations() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("src/a", "a");
+        fs.write("src/b", "b");
+
+        let prev = manifest("src/a\tdest/a\nsrc/b\tdest/x/y/b\n");
+        let next = manifest("src/a\tdest/a\n");
+
+        sync(&Manifest::new(), &prev, &options(fs.clone())).unwrap();
+        assert!(fs.exists("dest/x/y/b"));
+
+        assert_eq!(sync(&prev, &next, &options(fs.clone())).unwrap(), 0);
+        assert!(fs.exists("dest/a"));
+        assert!(!fs.exists("dest/x/y/b"));
+
+        // Empty parent directories are pruned, but not ones that still have
+        // something in them.
+        assert!(!fs.exists("dest/x"));
+        assert!(fs.exists("dest"));
+    }
+
+    #[test]
+    fn dryrun() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("src/a", "a");
+
+        let prev = manifest("src/a\tdest/old\n");
+        let next = manifest("src/a\tdest/a\n");
+
+        sync(&Manifest::new(), &prev, &options(fs.clone())).unwrap();
+
+        let paths = fs.paths();
+        assert_eq!(
+            sync(&prev, &next, &options(fs.clone()).dryrun(true)).unwrap(),
+            1
+        );
+        assert_eq!(fs.paths(), paths);
+    }
+
+    #[test]
+    fn missing_sources() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("src/a", "a");
+
+        let next = manifest("src/a\tdest/a\nsrc/missing\tdest/missing\n");
+
+        let err =
+            sync(&Manifest::new(), &next, &options(fs.clone())).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingSrcs);
+        assert!(!fs.exists("dest/a"));
+    }
+
+    #[test]
+    fn failed_deletions() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "a");
+        fs.inner().write("dest/old", "old");
+
+        let prev = manifest("src/a\tdest/old\n");
+        let next = manifest("src/a\tdest/a\n");
+
+        fs.fail(Operation::RemoveFile, "dest/old", 1, io::ErrorKind::Other);
+
+        let options = options(fs.clone());
+        let err = sync(&prev, &next, &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Delete);
+
+        // Nothing gets copied if a deletion fails.
+        assert!(!fs.inner().exists("dest/a"));
+
+        // Retrying works once the problem goes away.
+        assert_eq!(sync(&prev, &next, &options).unwrap(), 1);
+        assert!(!fs.inner().exists("dest/old"));
+    }
+
+    #[test]
+    fn failed_dir_deletions() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "a");
+        fs.inner().write("dest/x/old", "old");
+
+        let prev = manifest("src/a\tdest/x/old\n");
+        let next = manifest("src/a\tdest/a\n");
+
+        fs.fail(Operation::RemoveDir, "dest/x", 2, io::ErrorKind::Other);
+
+        let options = options(fs.clone()).retries(1);
+        let err = sync(&prev, &next, &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DeleteDirs);
+        assert_eq!(fs.calls(Operation::RemoveDir, "dest/x"), 2);
+    }
+
+    #[test]
+    fn failed_copies() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "a");
+        fs.inner().write("src/b", "b");
+
+        let next = manifest("src/a\tdest/a\nsrc/b\tdest/b\n");
+
+        fs.fail(Operation::Copy, "dest/b", 3, io::ErrorKind::TimedOut);
+
+        let options = options(fs.clone()).retries(2);
+
+        match sync(&Manifest::new(), &next, &options).unwrap_err() {
+            Error::Copy(failed) => {
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].0.dest, Path::new("dest/b"));
+                assert_eq!(failed[0].1.kind(), io::ErrorKind::TimedOut);
+            }
+            err => panic!("unexpected error: {:?}", err),
         };
+
+        // The other copy still happened.
+        assert!(fs.inner().exists("dest/a"));
+        assert_eq!(fs.calls(Operation::Copy, "dest/b"), 3);
     }
 
-    Ok(outdated.len())
+    #[test]
+    fn transient_metadata_errors() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "a");
+
+        let next = manifest("src/a\tdest/a\n");
+
+        fs.fail(Operation::Metadata, "src/a", 1, io::ErrorKind::TimedOut);
+
+        let options = options(fs.clone()).retries(1);
+        assert_eq!(sync(&Manifest::new(), &next, &options).unwrap(), 1);
+    }
+
+    #[test]
+    fn source_vanishes_during_copy() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "a");
+        fs.vanish_on_copy("src/a");
+
+        let next = manifest("src/a\tdest/a\n");
+
+        let options = options(fs.clone());
+        let err = sync(&Manifest::new(), &next, &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Copy);
+        assert!(!fs.inner().exists("src/a"));
+        assert!(!fs.inner().exists("dest/a"));
+    }
+
+    #[test]
+    fn short_writes_fail_verification() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "hello");
+        fs.short_write("src/a", 2);
+
+        let next = manifest("src/a\tdest/a\n");
+
+        let options = options(fs.clone());
+        let err = sync(&Manifest::new(), &next, &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::VerifyIncomplete);
+        assert_eq!(fs.inner().read("dest/a").unwrap(), b"he");
+    }
+
+    #[test]
+    fn races() {
+        let fs = Arc::new(MemoryFs::new());
+
+        let next = manifest("a\tb\nb\tc\n");
+        let err =
+            sync(&Manifest::new(), &next, &options(fs.clone())).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Overlap);
+
+        let next = manifest("a\tc\nb\tc\n");
+        let err =
+            sync(&Manifest::new(), &next, &options(fs.clone())).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Duplicates);
+    }
+
+    #[test]
+    fn keep_going() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "a");
+        fs.inner().write("src/b", "b");
+        fs.inner().write("dest/old", "old");
+
+        let prev = manifest("src/a\tdest/old\n");
+        let next = manifest(
+            "src/a\tdest/a\nsrc/b\tdest/b\nsrc/missing\tdest/missing\n",
+        );
+
+        fs.fail(Operation::RemoveFile, "dest/old", 1, io::ErrorKind::Other);
+        fs.fail(Operation::Copy, "dest/b", 1, io::ErrorKind::TimedOut);
+
+        let options = options(fs.clone()).keep_going(true);
+
+        match sync(&prev, &next, &options).unwrap_err() {
+            Error::Multiple(errors) => {
+                let kinds: Vec<_> = errors.iter().map(Error::kind).collect();
+                assert_eq!(
+                    kinds,
+                    [
+                        ErrorKind::Delete,
+                        ErrorKind::MissingSrcs,
+                        ErrorKind::Copy
+                    ]
+                );
+            }
+            err => panic!("unexpected error: {:?}", err),
+        };
+
+        // Everything unaffected by the failures still got copied.
+        assert_eq!(fs.inner().read("dest/a").unwrap(), b"a");
+        assert!(!fs.inner().exists("dest/b"));
+        assert!(fs.inner().exists("dest/old"));
+    }
+
+    #[test]
+    fn keep_going_skips_races() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.write("a", "a");
+        fs.write("d", "d");
+
+        let next = manifest("a\tc\nb\tc\nd\te\n");
+
+        let options = options(fs.clone()).keep_going(true);
+        let err = sync(&Manifest::new(), &next, &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Duplicates);
+        assert!(!fs.exists("c"));
+        assert_eq!(fs.read("e").unwrap(), b"d");
+    }
+
+    #[test]
+    fn keep_going_skips_failed_dirs() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "a");
+        fs.inner().write("src/b", "b");
+
+        let next = manifest("src/a\tx/a\nsrc/b\ty/b\n");
+
+        fs.fail(Operation::CreateDirAll, "x", 1, io::ErrorKind::Other);
+
+        let options = options(fs.clone()).keep_going(true);
+        let err = sync(&Manifest::new(), &next, &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::CreateDirs);
+        assert_eq!(fs.calls(Operation::Copy, "x/a"), 0);
+        assert_eq!(fs.inner().read("y/b").unwrap(), b"b");
+    }
+
+    #[test]
+    fn state_after_failure() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "a");
+        fs.inner().write("src/b", "b");
+
+        let prev = manifest("src/a\tdest/old1\nsrc/b\tdest/old2\n");
+        let next = manifest("src/a\tdest/a\nsrc/b\tdest/b\n");
+
+        sync(&Manifest::new(), &prev, &options(fs.clone())).unwrap();
+
+        fs.fail(Operation::RemoveFile, "dest/old2", 1, io::ErrorKind::Other);
+        fs.fail(Operation::Copy, "dest/b", 1, io::ErrorKind::Other);
+
+        let options = options(fs.clone()).keep_going(true);
+        let (state, result) = sync_with_state(&prev, &next, &options);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Multiple);
+
+        // Only what actually happened is recorded.
+        assert_eq!(
+            state.dests(),
+            [Path::new("dest/a"), Path::new("dest/old2")]
+        );
+
+        // Syncing from the recorded state finishes the job.
+        let (state, result) = sync_with_state(&state, &next, &options);
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(state.operations(), next.operations());
+        assert!(!fs.inner().exists("dest/old2"));
+    }
+
+    #[test]
+    fn state_after_missing_srcs() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "a");
+
+        let next = manifest("src/a\tdest/a\n");
+        sync(&Manifest::new(), &next, &options(fs.clone())).unwrap();
+
+        // The state was lost, but `dest/a` is already up-to-date.
+        let next = manifest("src/a\tdest/a\nsrc/missing\tdest/b\n");
+        let (state, result) =
+            sync_with_state(&Manifest::new(), &next, &options(fs.clone()));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::MissingSrcs);
+        assert_eq!(state.dests(), [Path::new("dest/a")]);
+    }
+
+    #[test]
+    fn sync_from_saved_state() {
+        let fs = Arc::new(FaultFs::new(MemoryFs::new()));
+        fs.inner().write("src/a", "a");
+        fs.inner().write("src/b", "b");
+
+        let text = "src/a\tdest/a\tmode=0644\nsrc/b\tdest/b\n";
+        let prev = manifest(text);
+        sync(&Manifest::new(), &prev, &options(fs.clone())).unwrap();
+
+        let mut data = Vec::new();
+        prev.write_state(&mut data).unwrap();
+        let prev = State::parse(&data[..], &ParseOptions::new()).unwrap();
+        assert_eq!(prev.dests(), [Path::new("dest/a"), Path::new("dest/b")]);
+
+        fs.fail(Operation::RemoveFile, "dest/a", 1, io::ErrorKind::Other);
+
+        let next = manifest("src/b\tdest/b\n");
+        let (state, result) =
+            sync_from_state(&prev, &next, &options(fs.clone()));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Delete);
+
+        // The destination that couldn't be deleted is decoded from the saved
+        // state along with everything else about it.
+        assert_eq!(state.dests(), [Path::new("dest/a"), Path::new("dest/b")]);
+        assert_eq!(state.operations()[0].mode, Some(0o644));
+
+        let (state, result) =
+            sync_from_state(&State::from(state), &next, &options(fs.clone()));
+        assert_eq!(result.unwrap(), 0);
+        assert_eq!(state.dests(), [Path::new("dest/b")]);
+        assert!(!fs.inner().exists("dest/a"));
+    }
 }
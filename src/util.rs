@@ -20,11 +20,14 @@
 
 use std::ffi;
 use std::fs;
-use std::io;
+use std::io::{self, Read, Write};
 use std::path::{Component, Path, PathBuf, Prefix};
+use std::process;
 use std::thread;
 use std::time::Duration;
 
+use crate::filesystem::{FileSystem, Metadata, RemoveDir};
+
 #[cfg(windows)]
 use kernel32;
 #[cfg(windows)]
@@ -36,16 +39,9 @@ use winapi::um::fileapi::INVALID_FILE_ATTRIBUTES;
 #[cfg(windows)]
 use winapi::um::winnt::{FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_READONLY};
 
-#[cfg(all(unix, not(any(target_os = "linux", target_os = "emscripten"))))]
-use libc::{lstat as lstat64, stat as stat64, timespec, utimensat, AT_FDCWD};
-#[cfg(any(target_os = "linux", target_os = "emscripten"))]
-use libc::{lstat64, stat64, timespec, utimensat, AT_FDCWD};
-
 #[cfg(unix)]
 use libc::{ENOENT, ENOTEMPTY};
 #[cfg(unix)]
-use std::mem;
-#[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
 
 /// Convert a string to UTF-16.
@@ -56,45 +52,46 @@ fn to_u16s<S: AsRef<ffi::OsStr>>(s: S) -> Vec<u16> {
     s
 }
 
-/// Wrapper for `remove_dir` to ignore certain types of errors. The value of the
-/// result indicates whether or not we can keep climbing the tree to delete more
-/// parent directories.
+/// Wrapper for `remove_dir` to ignore certain types of errors. The result
+/// tells whether or not we can keep climbing the tree to delete more parent
+/// directories.
 #[cfg(windows)]
-pub fn remove_dir(path: &Path) -> io::Result<bool> {
+pub fn remove_dir(path: &Path) -> io::Result<RemoveDir> {
     match fs::remove_dir(path) {
         Err(err) => match err.raw_os_error().unwrap() as u32 {
-            winerror::ERROR_FILE_NOT_FOUND => Ok(true),
-            winerror::ERROR_PATH_NOT_FOUND => Ok(true),
-            winerror::ERROR_DIR_NOT_EMPTY => Ok(false),
+            winerror::ERROR_FILE_NOT_FOUND => Ok(RemoveDir::Missing),
+            winerror::ERROR_PATH_NOT_FOUND => Ok(RemoveDir::Missing),
+            winerror::ERROR_DIR_NOT_EMPTY => Ok(RemoveDir::NotEmpty),
             _ => Err(err),
         },
-        Ok(()) => Ok(true),
+        Ok(()) => Ok(RemoveDir::Removed),
     }
 }
 
 #[cfg(unix)]
-pub fn remove_dir(path: &Path) -> io::Result<bool> {
+pub fn remove_dir(path: &Path) -> io::Result<RemoveDir> {
     match fs::remove_dir(path) {
         Err(err) => match err.raw_os_error().unwrap() {
-            ENOENT => Ok(true),
-            ENOTEMPTY => Ok(false),
+            ENOENT => Ok(RemoveDir::Missing),
+            ENOTEMPTY => Ok(RemoveDir::NotEmpty),
             _ => Err(err),
         },
-        Ok(()) => Ok(true),
+        Ok(()) => Ok(RemoveDir::Removed),
     }
 }
 
 /// Remove a directory with a retry.
 pub fn remove_dir_retry(
+    fs: &dyn FileSystem,
     path: &Path,
     retries: usize,
     delay: Duration,
-) -> io::Result<bool> {
-    match remove_dir(path) {
+) -> io::Result<RemoveDir> {
+    match fs.remove_dir(path) {
         Err(err) => {
             if retries > 0 {
                 thread::sleep(delay);
-                remove_dir_retry(path, retries - 1, delay * 2)
+                remove_dir_retry(fs, path, retries - 1, delay * 2)
             } else {
                 Err(err)
             }
@@ -104,19 +101,24 @@ pub fn remove_dir_retry(
 }
 
 /// Deletes a directory and all its parent directories until it reaches a
-/// directory that is not empty.
+/// directory that is not empty. `removed` is called for each directory that
+/// was deleted, but not for the ones that were already gone.
 pub fn remove_empty_dirs(
+    fs: &dyn FileSystem,
     path: &Path,
     retries: usize,
     delay: Duration,
+    removed: &dyn Fn(&Path),
 ) -> io::Result<()> {
-    if !remove_dir_retry(path, retries, delay)? {
-        return Ok(());
+    match remove_dir_retry(fs, path, retries, delay)? {
+        RemoveDir::Removed => removed(path),
+        RemoveDir::Missing => {}
+        RemoveDir::NotEmpty => return Ok(()),
     }
 
     if let Some(p) = path.removable_parent() {
         // Try to remove the parent directory as well.
-        remove_empty_dirs(p, retries, delay)
+        remove_empty_dirs(fs, p, retries, delay, removed)
     } else {
         Ok(())
     }
@@ -150,7 +152,7 @@ fn unset_attributes(path: &Path) -> io::Result<()> {
 /// Wrapper for `fs::remove_file` to ignore the case where the file or path to
 /// the file does not exist.
 #[cfg(windows)]
-fn remove_file(path: &Path) -> io::Result<()> {
+pub fn remove_file(path: &Path) -> io::Result<()> {
     match fs::remove_file(path) {
         Err(err) => {
             match err.kind() {
@@ -204,15 +206,16 @@ pub fn remove_file(path: &Path) -> io::Result<()> {
 /// Removes a file with a retry. This can be useful on Windows if someone has a
 /// lock on the file.
 pub fn remove_file_retry(
+    fs: &dyn FileSystem,
     path: &Path,
     retries: usize,
     delay: Duration,
 ) -> io::Result<()> {
-    match remove_file(path) {
+    match fs.remove_file(path) {
         Err(err) => {
             if retries > 0 {
                 thread::sleep(delay);
-                remove_file_retry(path, retries - 1, delay * 2)
+                remove_file_retry(fs, path, retries - 1, delay * 2)
             } else {
                 Err(err)
             }
@@ -221,91 +224,238 @@ pub fn remove_file_retry(
     }
 }
 
-/// Wraps `fs::copy` to be able to fix 'hidden' and 'readonly' attributes on the
-/// `to` path.
+/// The size of the chunks that files are copied in.
+#[cfg(unix)]
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
+/// Copies the contents, permissions and timestamps of a file a chunk at a
+/// time. `progress` is called with the total number of bytes written after
+/// each chunk.
+#[cfg(unix)]
+fn copy_chunked(
+    from: &Path,
+    to: &Path,
+    progress: &mut dyn FnMut(u64),
+) -> io::Result<u64> {
+    let mut reader = fs::File::open(from)?;
+    let metadata = reader.metadata()?;
+    let mut writer = fs::File::create(to)?;
+
+    // Small files don't need a whole chunk.
+    let len = metadata.len().clamp(1, COPY_CHUNK_SIZE as u64);
+    let mut buf = vec![0; len as usize];
+    let mut copied = 0;
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {
+                continue
+            }
+            Err(err) => return Err(err),
+        };
+
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+        progress(copied);
+    }
+
+    set_times(&writer, &metadata)?;
+    writer.set_permissions(metadata.permissions())?;
+
+    Ok(copied)
+}
+
+#[cfg(unix)]
+fn set_times(file: &fs::File, metadata: &fs::Metadata) -> io::Result<()> {
+    file.set_times(
+        fs::FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?),
+    )
+}
+
+/// The temporary file that a copy to `path` is written to before it is moved
+/// into place.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", process::id()));
+    path.with_file_name(name)
+}
+
+/// Copies `from` to a temporary file next to `to` with `copy` and then moves
+/// it into place. As `to` is replaced rather than written to, other hard links
+/// to it and the target of a symlink at `to` are left alone. If the copy
+/// fails, the temporary file is removed and `to` is left as it was.
+fn copy_via_temp<F>(from: &Path, to: &Path, copy: F) -> io::Result<u64>
+where
+    F: FnOnce(&Path, &Path) -> io::Result<u64>,
+{
+    let temp = temp_path(to);
+
+    // A copy that was cut short by a crash may have left this behind.
+    remove_file(&temp)?;
+
+    let result = copy(from, &temp).and_then(|n| replace(&temp, to).map(|()| n));
+
+    if result.is_err() {
+        let _ = remove_file(&temp);
+    }
+
+    result
+}
+
+/// Moves `from` over `to`, fixing 'hidden' and 'readonly' attributes on the
+/// `to` path if they get in the way.
 #[cfg(windows)]
-pub fn copy(from: &Path, to: &Path) -> io::Result<u64> {
-    match fs::copy(from, to) {
+fn replace(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
         Err(err) => {
             if err.kind() == io::ErrorKind::PermissionDenied {
-                // Unset read-only and hidden attributes and try the copy
-                // again. Windows will fail to copy over files with these
+                // Unset read-only and hidden attributes and try the rename
+                // again. Windows will fail to replace files with these
                 // attributes set.
                 if let Err(err) = unset_attributes(to) {
                     Err(err)
                 } else {
                     // Try again.
-                    fs::copy(from, to)
+                    fs::rename(from, to)
                 }
             } else {
                 Err(err)
             }
         }
-        Ok(n) => Ok(n),
+        Ok(()) => Ok(()),
     }
 }
 
 #[cfg(unix)]
-fn lstat(p: &Path) -> io::Result<stat64> {
-    let p = ffi::CString::new(p.as_os_str().as_bytes())?;
-
-    let mut stat: stat64 = unsafe { mem::zeroed() };
+fn replace(from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to)
+}
 
-    let ret = unsafe { lstat64(p.as_ptr(), &mut stat as *mut stat64) };
+/// Copies a file by way of `copy_via_temp`.
+///
+/// This always uses `fs::copy` so that `CopyFileEx` carries over the file
+/// attributes and alternate data streams. Progress is only reported once the
+/// copy is done.
+#[cfg(windows)]
+pub fn copy(
+    from: &Path,
+    to: &Path,
+    progress: Option<&mut dyn FnMut(u64)>,
+) -> io::Result<u64> {
+    let copied = copy_via_temp(from, to, |from, to| fs::copy(from, to))?;
 
-    if ret == -1 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(stat)
+    if let Some(progress) = progress {
+        if copied > 0 {
+            progress(copied);
+        }
     }
+
+    Ok(copied)
 }
 
+/// Copies the contents, permissions and timestamps of a file by way of
+/// `copy_via_temp`. Without `progress`, this uses `fs::copy` so that the
+/// fastest way of copying that the system has (e.g., `copy_file_range` or
+/// reflinks) is used.
 #[cfg(unix)]
-fn copy_timestamps(from: &Path, to: &Path) -> io::Result<()> {
-    let to = ffi::CString::new(to.as_os_str().as_bytes())?;
+pub fn copy(
+    from: &Path,
+    to: &Path,
+    progress: Option<&mut dyn FnMut(u64)>,
+) -> io::Result<u64> {
+    if let Some(progress) = progress {
+        return copy_via_temp(from, to, |from, to| {
+            copy_chunked(from, to, progress)
+        });
+    }
 
-    let stat = lstat(from)?;
+    copy_via_temp(from, to, |from, to| {
+        let copied = fs::copy(from, to)?;
 
-    let times: [timespec; 2] = [
-        timespec {
-            tv_sec: stat.st_atime,
-            tv_nsec: stat.st_atime_nsec,
-        },
-        timespec {
-            tv_sec: stat.st_mtime,
-            tv_nsec: stat.st_mtime_nsec,
-        },
-    ];
+        // `fs::copy` doesn't carry over the timestamps, which are needed to
+        // tell that the copy is complete.
+        set_times(&fs::File::open(to)?, &fs::metadata(from)?)?;
 
-    let ret = unsafe {
-        utimensat(AT_FDCWD, to.as_ptr(), &times as *const timespec, 0)
-    };
+        Ok(copied)
+    })
+}
 
-    if ret == -1 {
-        Err(io::Error::last_os_error())
+/// The permission bits of a file.
+#[cfg(unix)]
+pub fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub fn mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
     } else {
-        Ok(())
+        0o644
     }
 }
 
+/// The device and inode numbers of a file.
+#[cfg(unix)]
+pub fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
 #[cfg(unix)]
-pub fn copy(from: &Path, to: &Path) -> io::Result<u64> {
-    let n = fs::copy(from, to)?;
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
 
-    copy_timestamps(from, to)?;
+/// Only the readonly flag can be set here. It is set if the owner can't write
+/// to the file.
+#[cfg(not(unix))]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+}
 
-    Ok(n)
+#[cfg(unix)]
+pub fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+/// The target is assumed to be a file. Creating symlinks may require extra
+/// privileges on Windows.
+#[cfg(windows)]
+pub fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, path)
 }
 
 /// Copies a file with a retry. When copying files across the network, this can
 /// be useful to work around transient failures.
 pub fn copy_retry(
+    fs: &dyn FileSystem,
     from: &Path,
     to: &Path,
     retries: usize,
     delay: Duration,
+    mut progress: Option<&mut dyn FnMut(u64)>,
 ) -> io::Result<u64> {
-    match copy(from, to) {
+    // Reborrow the callback so that it can be used again for the retry.
+    let reborrowed = progress
+        .as_mut()
+        .map(|progress| &mut **progress as &mut dyn FnMut(u64));
+
+    match fs.copy(from, to, reborrowed) {
         Err(err) => {
             match err.kind() {
                 // These errors are not worth retrying as they almost never
@@ -318,7 +468,14 @@ pub fn copy_retry(
                 _ => {
                     if retries > 0 {
                         thread::sleep(delay);
-                        copy_retry(from, to, retries - 1, delay * 2)
+                        copy_retry(
+                            fs,
+                            from,
+                            to,
+                            retries - 1,
+                            delay * 2,
+                            progress,
+                        )
                     } else {
                         Err(err)
                     }
@@ -331,11 +488,12 @@ pub fn copy_retry(
 
 /// Get metadata with a retry.
 pub fn metadata_retry(
+    fs: &dyn FileSystem,
     path: &Path,
     retries: usize,
     delay: Duration,
-) -> io::Result<fs::Metadata> {
-    match fs::metadata(path) {
+) -> io::Result<Metadata> {
+    match fs.metadata(path) {
         Err(err) => {
             match err.kind() {
                 // These errors are not worth retrying as they almost never
@@ -348,7 +506,7 @@ pub fn metadata_retry(
                 _ => {
                     if retries > 0 {
                         thread::sleep(delay);
-                        metadata_retry(path, retries - 1, delay * 2)
+                        metadata_retry(fs, path, retries - 1, delay * 2)
                     } else {
                         Err(err)
                     }
@@ -464,11 +622,7 @@ impl PathExt for Path {
 
     fn is_sandboxed(&self) -> bool {
         if let Some(c) = self.components().next() {
-            match c {
-                Component::CurDir => true,
-                Component::Normal(_) => true,
-                _ => false,
-            }
+            matches!(c, Component::CurDir | Component::Normal(_))
         } else {
             // Nothing in the path. It can be considered sandboxed.
             true
@@ -480,10 +634,164 @@ impl PathExt for Path {
     }
 }
 
+/// Converts the raw bytes of a path into a `PathBuf`. On Unix, a path can be
+/// any sequence of bytes. Elsewhere, it must be valid UTF-8. If not, the bytes
+/// are given back.
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf, Vec<u8>> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(PathBuf::from(ffi::OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf, Vec<u8>> {
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|err| err.into_bytes())
+}
+
+/// Borrows the raw bytes of a path as a `Path`. Like `path_from_bytes`, they
+/// must be valid UTF-8 outside of Unix.
+#[cfg(unix)]
+pub fn path_from_slice(bytes: &[u8]) -> Option<&Path> {
+    Some(Path::new(ffi::OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_slice(bytes: &[u8]) -> Option<&Path> {
+    std::str::from_utf8(bytes).ok().map(Path::new)
+}
+
+/// The raw bytes of a path. This is the inverse of `path_from_bytes`. Returns
+/// `None` if the path can't be represented as bytes.
+#[cfg(unix)]
+pub fn path_as_bytes(path: &Path) -> Option<&[u8]> {
+    Some(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn path_as_bytes(path: &Path) -> Option<&[u8]> {
+    path.to_str().map(str::as_bytes)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::faultfs::{FaultFs, Operation};
+    use crate::memfs::MemoryFs;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_progress() {
+        let dir = std::env::temp_dir()
+            .join(format!("ubercopy-copy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let len = COPY_CHUNK_SIZE as u64 * 5 / 2;
+        fs::write(&a, vec![1; len as usize]).unwrap();
+
+        let mut progress = Vec::new();
+        let copied = copy(&a, &b, Some(&mut |n| progress.push(n))).unwrap();
+        assert_eq!(copied, len);
+
+        let chunk = COPY_CHUNK_SIZE as u64;
+        assert_eq!(progress, [chunk, chunk * 2, len]);
+
+        let (ma, mb) = (fs::metadata(&a).unwrap(), fs::metadata(&b).unwrap());
+        assert_eq!(ma.modified().unwrap(), mb.modified().unwrap());
+        assert_eq!(ma.permissions(), mb.permissions());
+
+        // Without progress, the copy is the same.
+        let c = dir.join("c");
+        assert_eq!(copy(&a, &c, None).unwrap(), len);
+        let mc = fs::metadata(&c).unwrap();
+        assert_eq!(ma.modified().unwrap(), mc.modified().unwrap());
+        assert_eq!(ma.permissions(), mc.permissions());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_failed_copy() {
+        let dir = std::env::temp_dir()
+            .join(format!("ubercopy-failed-copy-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("dest"), "old").unwrap();
+
+        // Reading a directory only fails once the copy has started.
+        let (src, dest) = (dir.join("src"), dir.join("dest"));
+        assert!(copy(&src, &dest, Some(&mut |_| ())).is_err());
+        assert!(copy(&src, &dest, None).is_err());
+
+        // Only the copy's own temporary file is removed.
+        assert_eq!(fs::read(&dest).unwrap(), b"old");
+        assert!(!temp_path(&dest).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_copy_retry() {
+        let fs = FaultFs::new(MemoryFs::new());
+        fs.inner().write("a", "a");
+
+        let (a, b) = (Path::new("a"), Path::new("b"));
+
+        // Transient errors are retried until we run out of retries.
+        fs.fail(Operation::Copy, "b", 2, io::ErrorKind::TimedOut);
+        assert_eq!(copy_retry(&fs, a, b, 2, Duration::ZERO, None).unwrap(), 1);
+        assert_eq!(fs.calls(Operation::Copy, b), 3);
+
+        fs.fail(Operation::Copy, "b", 3, io::ErrorKind::TimedOut);
+        assert!(copy_retry(&fs, a, b, 2, Duration::ZERO, None).is_err());
+        assert_eq!(fs.calls(Operation::Copy, b), 6);
+
+        // Some errors are never retried.
+        fs.fail(Operation::Copy, "b", 1, io::ErrorKind::PermissionDenied);
+        assert!(copy_retry(&fs, a, b, 2, Duration::ZERO, None).is_err());
+        assert_eq!(fs.calls(Operation::Copy, b), 7);
+    }
+
+    #[test]
+    fn test_metadata_retry() {
+        let fs = FaultFs::new(MemoryFs::new());
+        fs.inner().write("a", "a");
+
+        let a = Path::new("a");
+
+        fs.fail(Operation::Metadata, "a", 1, io::ErrorKind::Interrupted);
+        assert_eq!(metadata_retry(&fs, a, 1, Duration::ZERO).unwrap().len, 1);
+
+        fs.fail(Operation::Metadata, "a", 1, io::ErrorKind::NotFound);
+        assert!(metadata_retry(&fs, a, 1, Duration::ZERO).is_err());
+        assert_eq!(fs.calls(Operation::Metadata, a), 3);
+    }
+
+    #[test]
+    fn test_remove_retry() {
+        let fs = FaultFs::new(MemoryFs::new());
+        fs.inner().write("a/b", "b");
+
+        let (a, b) = (Path::new("a"), Path::new("a/b"));
+
+        // Deletions are retried regardless of the error.
+        fs.fail(Operation::RemoveFile, b, 2, io::ErrorKind::PermissionDenied);
+        assert!(remove_file_retry(&fs, b, 1, Duration::ZERO).is_err());
+        remove_file_retry(&fs, b, 1, Duration::ZERO).unwrap();
+        assert!(!fs.inner().exists(b));
+
+        fs.fail(Operation::RemoveDir, a, 1, io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            remove_dir_retry(&fs, a, 1, Duration::ZERO).unwrap(),
+            RemoveDir::Removed
+        );
+        assert_eq!(fs.calls(Operation::RemoveDir, a), 2);
+    }
+
     #[test]
     fn test_sandbox() {
         assert!(Path::new("foo").is_sandboxed());
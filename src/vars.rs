@@ -0,0 +1,149 @@
+// Copyright (c) 2019 Jason White
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Expands `${VAR}` and `${VAR:-default}` references in manifest paths.
+
+/// Returns the position of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Returns `true` if `name` can be used as a variable name. Like in a shell,
+/// it must be made of letters, digits and underscores and not start with a
+/// digit.
+fn is_valid_name(name: &[u8]) -> bool {
+    match name.first() {
+        Some(c) if !c.is_ascii_digit() => {
+            name.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'_')
+        }
+        _ => false,
+    }
+}
+
+/// Returns `true` if `text` has any variable references in it.
+pub fn has_vars(text: &[u8]) -> bool {
+    find(text, b"${").is_some()
+}
+
+/// Replaces every `${VAR}` in `text` with the value that `lookup` gives for
+/// `VAR`. With `${VAR:-default}`, the default is used if the variable is
+/// undefined or empty. A `$` that isn't followed by `{` is left alone.
+///
+/// All of the problems that are found are returned together.
+pub fn expand<F>(text: &[u8], lookup: F) -> Result<Vec<u8>, Vec<String>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut expanded = Vec::with_capacity(text.len());
+    let mut errors = Vec::new();
+    let mut rest = text;
+
+    while let Some(i) = find(rest, b"${") {
+        expanded.extend_from_slice(&rest[..i]);
+
+        let reference = &rest[i + 2..];
+
+        let end = match reference.iter().position(|&c| c == b'}') {
+            Some(end) => end,
+            None => {
+                errors.push("Unterminated variable reference".to_string());
+                rest = &[];
+                break;
+            }
+        };
+
+        let body = &reference[..end];
+        rest = &reference[end + 1..];
+
+        let (name, default) = match find(body, b":-") {
+            Some(j) => (&body[..j], Some(&body[j + 2..])),
+            None => (body, None),
+        };
+
+        let name = match std::str::from_utf8(name) {
+            Ok(name) if is_valid_name(name.as_bytes()) => name,
+            _ => {
+                errors.push(format!(
+                    "Invalid variable name {:?}",
+                    String::from_utf8_lossy(name)
+                ));
+                continue;
+            }
+        };
+
+        match (lookup(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => {
+                expanded.extend_from_slice(default)
+            }
+            (Some(value), _) => expanded.extend_from_slice(value.as_bytes()),
+            (None, Some(default)) => expanded.extend_from_slice(default),
+            (None, None) => errors.push(format!("Undefined variable {}", name)),
+        };
+    }
+
+    expanded.extend_from_slice(rest);
+
+    if errors.is_empty() {
+        Ok(expanded)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "ROOT" => Some("/build".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    fn expand_str(text: &str) -> Result<String, Vec<String>> {
+        expand(text.as_bytes(), lookup)
+            .map(|bytes| String::from_utf8(bytes).unwrap())
+    }
+
+    #[test]
+    fn references() {
+        assert_eq!(expand_str("${ROOT}/a").unwrap(), "/build/a");
+        assert_eq!(expand_str("a/${ROOT}${ROOT}").unwrap(), "a//build/build");
+        assert_eq!(expand_str("${MISSING:-out}/a").unwrap(), "out/a");
+        assert_eq!(expand_str("${EMPTY:-x}/${ROOT:-x}").unwrap(), "x//build");
+        assert_eq!(expand_str("${EMPTY}a").unwrap(), "a");
+        assert_eq!(expand_str("$ROOT/$/{a}").unwrap(), "$ROOT/$/{a}");
+    }
+
+    #[test]
+    fn problems() {
+        assert_eq!(
+            expand_str("${A}/${ROOT}/${B:-b}/${1X}/${}/${C").unwrap_err(),
+            [
+                "Undefined variable A",
+                "Invalid variable name \"1X\"",
+                "Invalid variable name \"\"",
+                "Unterminated variable reference",
+            ]
+        );
+    }
+}